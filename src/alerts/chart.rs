@@ -1,10 +1,12 @@
-use crate::alerts::config::{Alert, AlertCondition, Condition};
-use crate::alerts::request_values;
-use chrono::Utc;
+use crate::alerts::config::{Alert, AlertCondition, AlertStatus, Condition};
+use crate::alerts::{reducers, request_query, request_values, Values};
+use crate::util::formatted_duration;
+use chrono::{DateTime, Utc};
 use plotters::prelude::{
-  BitMapBackend, ChartBuilder, Color, IntoDrawingArea, IntoFont, IntoTextStyle, LineSeries, PathElement, Polygon,
-  RGBColor, TRANSPARENT,
+  BitMapBackend, ChartBuilder, Circle, Color, IntoDrawingArea, IntoFont, IntoTextStyle, LineSeries, PathElement,
+  Polygon, RGBColor, TRANSPARENT,
 };
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::{BufReader, Read};
 use tempfile::tempdir;
@@ -37,16 +39,105 @@ pub async fn generate_chart(
   alert: &Alert,
   start: i64,
   end: i64,
-  draw_label: Option<String>,
+  draw_labels: Option<Vec<String>>,
 ) -> anyhow::Result<Vec<u8>> {
   let values = request_values(alert, start, end).await;
+  let baseline = match alert.condition {
+    AlertCondition::Anomaly { baseline_range_s, .. } => {
+      let baseline_end = end - alert.condition_range_s as i64;
+      match request_values(alert, end - baseline_range_s as i64, baseline_end).await {
+        Ok(baseline) => baseline,
+        Err(err) => {
+          log::error!("Failed to request baseline for {}: {:?}", alert.name, err);
+          HashMap::new()
+        }
+      }
+    }
+    _ => HashMap::new(),
+  };
+  // Previous period of Compare condition, shifted to current range
+  let previous = match alert.compare_offset_s() {
+    Some(offset) => match request_values(alert, start - offset as i64, end - offset as i64).await {
+      Ok(previous) => previous
+        .into_iter()
+        .map(|(label, values)| {
+          let values = values.into_iter().map(|(t, v)| (t + offset, v)).collect::<Values>();
+          (label, values)
+        })
+        .collect(),
+      Err(err) => {
+        log::error!("Failed to request previous period for {}: {:?}", alert.name, err);
+        HashMap::new()
+      }
+    },
+    None => HashMap::new(),
+  };
+  let raw_series = if alert.is_ratio() && alert.graph_raw_series {
+    match request_raw_series(alert, start, end, &draw_labels).await {
+      Ok(raw_series) => raw_series,
+      Err(err) => {
+        log::error!("Failed to request raw series for {}: {:?}", alert.name, err);
+        vec![]
+      }
+    }
+  } else {
+    vec![]
+  };
 
   ////
   // Get constraints
   ////
 
+  // Forecast is drawn as projected line from condition start to the horizon, X axis is extended up to it
+  let mut axis_end = end;
+  let mut projections: HashMap<String, Vec<(DateTime<Utc>, f32)>> = HashMap::new();
+  if let (AlertCondition::Forecast { horizon_s, .. }, Ok(values)) = (&alert.condition, &values) {
+    let condition_start = (end - alert.condition_range_s as i64) as u64;
+    axis_end = end + *horizon_s as i64;
+
+    for (label, values) in values {
+      if !is_drawn(&draw_labels, label) {
+        continue;
+      }
+
+      let window = condition_window(values, condition_start);
+      let projection = vec![
+        (
+          parse_time(condition_start as i64),
+          reducers::forecast(&window, condition_start),
+        ),
+        (parse_time(axis_end), reducers::forecast(&window, axis_end as u64)),
+      ];
+      projections.insert(label.clone(), projection);
+    }
+  }
+
+  // Sum and count are not on the same scale as samples, they are drawn on secondary axis over condition range
+  let mut reduced: Vec<(String, f32, RGBColor)> = vec![];
+  if let (AlertCondition::Sum { .. } | AlertCondition::Count { .. }, Ok(values)) = (&alert.condition, &values) {
+    let condition_start = (end - alert.condition_range_s as i64) as u64;
+    let mut keys = values.keys().collect::<Vec<_>>();
+    keys.sort();
+
+    for (index, label) in keys.into_iter().enumerate() {
+      if !is_drawn(&draw_labels, label) {
+        continue;
+      }
+
+      let window = condition_window(&values[label], condition_start);
+      let value = match alert.condition {
+        AlertCondition::Sum { .. } => reducers::sum(&window),
+        _ => reducers::count(&window),
+      };
+      reduced.push((label.clone(), value, label_color(index)));
+    }
+  }
+
   let end = parse_time(end);
   let start = parse_time(start);
+  let axis_end = parse_time(axis_end);
+  // Step is validated in init_config
+  let step = alert.step_s().unwrap_or(10);
 
   let mut min = alert.graph_min;
   let mut max = alert.graph_max;
@@ -54,11 +145,22 @@ pub async fn generate_chart(
   let name = match &values {
     Ok(values) => {
       for (label, values) in values {
-        // Skip not matching if draw_label is provided
-        if let Some(draw_label) = draw_label.clone() {
-          if !label.eq(&draw_label) {
-            continue;
-          }
+        if !is_drawn(&draw_labels, label) {
+          continue;
+        }
+
+        for (_, value) in values {
+          min = min.min(*value);
+          max = max.max(*value);
+        }
+      }
+      for (_, value) in projections.values().flatten() {
+        min = min.min(*value);
+        max = max.max(*value);
+      }
+      for (label, values) in &previous {
+        if !is_drawn(&draw_labels, label) {
+          continue;
         }
 
         for (_, value) in values {
@@ -86,6 +188,29 @@ pub async fn generate_chart(
     return Err(anyhow::anyhow!("No data"));
   }
 
+  let (mut raw_min, mut raw_max) = (f32::MAX, f32::MIN);
+  for (_, value) in raw_series.iter().flat_map(|(_, values)| values) {
+    raw_min = raw_min.min(*value * 0.95);
+    raw_max = raw_max.max(*value * 1.05);
+  }
+  if !reduced.is_empty() {
+    let (condition, _, _) = alert.condition.threshold();
+    let bounds = alert
+      .thresholds()
+      .iter()
+      .flat_map(|(_, value, _)| condition.bounds(*value))
+      .collect::<Vec<_>>();
+
+    for value in reduced.iter().map(|(_, value, _)| *value).chain(bounds) {
+      raw_min = raw_min.min(value * 0.95);
+      raw_max = raw_max.max(value * 1.05);
+    }
+  }
+  if raw_max < raw_min {
+    raw_min = 0.0;
+    raw_max = 1.0;
+  }
+
   ////
   // Render
   ////
@@ -100,8 +225,13 @@ pub async fn generate_chart(
     .margin(10)
     .x_label_area_size(40)
     .y_label_area_size(40)
+    .right_y_label_area_size(if raw_series.is_empty() && reduced.is_empty() {
+      0
+    } else {
+      40
+    })
     .caption(name, ("sans-serif", 30.0).into_font().with_color(WHITE))
-    .build_cartesian_2d(start..end, min..max)?;
+    .build_cartesian_2d(start..axis_end, min..max)?;
 
   chart
     .configure_mesh()
@@ -116,42 +246,240 @@ pub async fn generate_chart(
     keys.sort();
 
     for label in keys.clone() {
-      // Skip not matching if draw_label is provided
-      if let Some(draw_label) = draw_label.clone() {
-        if label != draw_label {
-          continue;
-        }
+      if !is_drawn(&draw_labels, &label) {
+        continue;
       }
 
       let metric_values = values.get(&label).unwrap();
       let color = label_color(keys.iter().position(|v| v.eq(&label)).unwrap_or(0));
 
-      chart
-        .draw_series(LineSeries::new(
-          metric_values
+      // Line is broken where samples are missing, legend is attached to the first segment
+      for (index, segment) in split_gaps(metric_values, step).into_iter().enumerate() {
+        let series = chart.draw_series(LineSeries::new(
+          segment
             .iter()
             .map(|(timestamp, value)| (parse_time(*timestamp as i64), *value)),
           &color,
-        ))?
-        .label(&label)
-        .legend(move |(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], color));
-    }
-
-    let error_polygon = match alert.condition.clone() {
-      AlertCondition::Avg {
-        condition,
-        value,
-        value_ok: _,
-      } => match condition {
-        Condition::Less => {
-          vec![(start, min), (end, min), (end, value), (start, value)]
+        ))?;
+
+        if index == 0 {
+          series
+            .label(&label)
+            .legend(move |(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], color));
         }
-        Condition::Greater => {
-          vec![(start, value), (end, value), (end, max), (start, max)]
+      }
+    }
+
+    // Previous period is dashed, every second segment between samples is skipped
+    let offset = formatted_duration(chrono::Duration::seconds(alert.compare_offset_s().unwrap_or(0) as i64));
+    for label in &keys {
+      let previous_values = match previous.get(label) {
+        Some(previous_values) if is_drawn(&draw_labels, label) => previous_values,
+        _ => continue,
+      };
+      let color = label_color(keys.iter().position(|v| v.eq(label)).unwrap_or(0)).mix(0.6);
+
+      chart
+        .draw_series(
+          split_gaps(previous_values, step)
+            .into_iter()
+            .flat_map(|samples| samples.windows(2).step_by(2))
+            .map(|segment| {
+              PathElement::new(
+                segment
+                  .iter()
+                  .map(|(timestamp, value)| (parse_time(*timestamp as i64), *value))
+                  .collect::<Vec<_>>(),
+                color,
+              )
+            }),
+        )?
+        .label(format!("{} ({} ago)", label, offset.trim()))
+        .legend(move |(x, y)| PathElement::new(vec![(x, y), (x + 8, y)], color));
+    }
+
+    for (label, projection) in projections {
+      let color = label_color(keys.iter().position(|v| v.eq(&label)).unwrap_or(0));
+
+      chart.draw_series(LineSeries::new(projection, &color.mix(0.5)))?;
+    }
+
+    // Polygons of each tier are drawn on top of each other, so more severe areas are brighter
+    let (condition, _, _) = alert.condition.threshold();
+    let thresholds = alert.thresholds();
+    let error_polygons = match &alert.condition {
+      AlertCondition::Avg { .. }
+      | AlertCondition::Max { .. }
+      | AlertCondition::Min { .. }
+      | AlertCondition::Last { .. }
+      | AlertCondition::Percentile { .. }
+      | AlertCondition::Forecast { .. } => thresholds
+        .iter()
+        .flat_map(|(_, value, _)| threshold_polygons(condition, *value, start, axis_end, min, max))
+        .collect(),
+      // Sum and count are drawn on secondary axis, difference with previous period is not on the same scale as
+      // samples, threshold can't be drawn on this chart
+      AlertCondition::Sum { .. }
+      | AlertCondition::Count { .. }
+      | AlertCondition::Compare { .. }
+      | AlertCondition::Expression { .. } => vec![],
+      // Change is not on the same scale either, mark windows where it was detected instead
+      AlertCondition::Change { method, .. } => {
+        let condition_start = end.timestamp() as u64 - alert.condition_range_s;
+        let mut polygons = vec![];
+
+        for label in &keys {
+          if !is_drawn(&draw_labels, label) {
+            continue;
+          }
+
+          let window = condition_window(&values[label], condition_start);
+          let (change, from, to) = reducers::change(&window, method, condition);
+          if alert.breached_status(change, &AlertStatus::Ok).is_some() {
+            let (from, to) = (parse_time(from as i64), parse_time(to as i64));
+            polygons.push(vec![(from, min), (to, min), (to, max), (from, max)]);
+          }
         }
-      },
+
+        polygons
+      }
+      // Samples matching threshold are highlighted, so share of them is visible
+      AlertCondition::DutyCycle {
+        condition, threshold, ..
+      } => {
+        for label in &keys {
+          if !is_drawn(&draw_labels, label) {
+            continue;
+          }
+
+          chart.draw_series(
+            values[label]
+              .iter()
+              .filter(|(_, value)| condition.matches(*value, *threshold, false))
+              .map(|(timestamp, value)| {
+                Circle::new((parse_time(*timestamp as i64), *value), 3, ERROR_POLYGON.filled())
+              }),
+          )?;
+        }
+
+        threshold_polygons(condition, *threshold, start, axis_end, min, max)
+      }
+      // Thresholds are in standard deviations from baseline, draw them around baseline mean
+      AlertCondition::Anomaly { min_std, .. } => {
+        let mut polygons = vec![];
+
+        for label in &keys {
+          if !is_drawn(&draw_labels, label) {
+            continue;
+          }
+          let (mean, std) = match baseline.get(label) {
+            Some(values) => reducers::mean_std(values),
+            None => continue,
+          };
+
+          let color = label_color(keys.iter().position(|v| v.eq(label)).unwrap_or(0));
+          chart.draw_series(LineSeries::new(vec![(start, mean), (end, mean)], &color.mix(0.5)))?;
+          // Condition gives no verdict on such baseline
+          if std.is_nan() || std <= *min_std {
+            continue;
+          }
+
+          let deviation = |sigma: f32| mean + sigma * std;
+          // Band of ±N sigma around mean, bands of condition are drawn as is
+          for (_, value, _) in &thresholds {
+            let (low, high) = match *condition {
+              Condition::Outside { low, high, .. } | Condition::Inside { low, high, .. } => {
+                (deviation(low), deviation(high))
+              }
+              _ => (deviation(-value.abs()), deviation(value.abs())),
+            };
+
+            chart.draw_series(std::iter::once(Polygon::new(
+              vec![(start, low), (end, low), (end, high), (start, high)],
+              color.mix(0.1),
+            )))?;
+          }
+          let condition = match *condition {
+            Condition::Outside { low, high, margin } => Condition::Outside {
+              low: deviation(low),
+              high: deviation(high),
+              margin,
+            },
+            Condition::Inside { low, high, margin } => Condition::Inside {
+              low: deviation(low),
+              high: deviation(high),
+              margin,
+            },
+            ref condition => condition.clone(),
+          };
+          for (_, value, _) in &thresholds {
+            polygons.append(&mut threshold_polygons(
+              &condition,
+              deviation(*value),
+              start,
+              end,
+              min,
+              max,
+            ));
+          }
+        }
+
+        polygons
+      }
     };
-    chart.draw_series(std::iter::once(Polygon::new(error_polygon, &ERROR_POLYGON.mix(0.09))))?;
+    for error_polygon in error_polygons {
+      chart.draw_series(std::iter::once(Polygon::new(error_polygon, &ERROR_POLYGON.mix(0.09))))?;
+    }
+  }
+
+  // Numerator and denominator of ratio, sum and count are drawn on secondary axis
+  let mut chart = chart.set_secondary_coord(start..axis_end, raw_min..raw_max);
+  if !raw_series.is_empty() || !reduced.is_empty() {
+    chart
+      .configure_secondary_axes()
+      .axis_style(WHITE)
+      .label_style(("sans-serif", 16).into_font().color(&WHITE))
+      .draw()?;
+  }
+  if !reduced.is_empty() {
+    let condition_start = end - chrono::Duration::seconds(alert.condition_range_s as i64);
+    let (condition, _, _) = alert.condition.threshold();
+
+    for (_, value, _) in alert.thresholds() {
+      for polygon in threshold_polygons(condition, value, condition_start, end, raw_min, raw_max) {
+        chart.draw_secondary_series(std::iter::once(Polygon::new(polygon, ERROR_POLYGON.mix(0.09))))?;
+      }
+    }
+
+    for (label, value, color) in reduced {
+      chart
+        .draw_secondary_series(LineSeries::new(
+          vec![(condition_start, value), (end, value)],
+          &color.mix(0.6),
+        ))?
+        .label(format!("{} ({} = {:.2})", label, alert.condition.reducer_name(), value))
+        .legend(move |(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], color.mix(0.6)));
+    }
+  }
+  if !raw_series.is_empty() {
+    for (index, (label, values)) in raw_series.into_iter().enumerate() {
+      let color = label_color(index).mix(0.6);
+
+      for (segment_index, segment) in split_gaps(&values, step).into_iter().enumerate() {
+        let series = chart.draw_secondary_series(LineSeries::new(
+          segment
+            .iter()
+            .map(|(timestamp, value)| (parse_time(*timestamp as i64), *value)),
+          &color,
+        ))?;
+
+        if segment_index == 0 {
+          series
+            .label(label.clone())
+            .legend(move |(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], color));
+        }
+      }
+    }
   }
 
   chart
@@ -178,6 +506,91 @@ pub async fn generate_chart(
   Ok(png_data)
 }
 
+/// Returns numerator and denominator series of ratio alert, sorted by label
+async fn request_raw_series(
+  alert: &Alert,
+  start: i64,
+  end: i64,
+  draw_labels: &Option<Vec<String>>,
+) -> anyhow::Result<Vec<(String, Values)>> {
+  let mut result = vec![];
+
+  for (name, query) in [
+    ("numerator", &alert.numerator_query),
+    ("denominator", &alert.denominator_query),
+  ] {
+    for (label, values) in request_query(alert, query, start, end).await? {
+      if !is_drawn(draw_labels, &label) {
+        continue;
+      }
+
+      result.push((format!("{} ({})", label, name), values));
+    }
+  }
+  result.sort_by(|a, b| a.0.cmp(&b.0));
+
+  Ok(result)
+}
+
+/// Skip not matching if draw_labels are provided
+fn is_drawn(draw_labels: &Option<Vec<String>>, label: &str) -> bool {
+  match draw_labels {
+    Some(draw_labels) => draw_labels.iter().any(|draw_label| draw_label == label),
+    None => true,
+  }
+}
+
+/// Splits samples where distance between them is larger than step, missing samples are not interpolated.
+/// Samples sparser than step, like Graphite series with retention of minutes, are split by their own spacing
+fn split_gaps(values: &[(u64, f32)], step: u64) -> Vec<&[(u64, f32)]> {
+  let spacing = values
+    .windows(2)
+    .map(|pair| pair[1].0.saturating_sub(pair[0].0))
+    .filter(|distance| *distance > 0)
+    .min()
+    .unwrap_or(step);
+  let step = step.max(spacing);
+
+  let mut result = vec![];
+  let mut segment_start = 0;
+
+  for index in 1..=values.len() {
+    if index == values.len() || values[index].0.saturating_sub(values[index - 1].0) > step + step / 2 {
+      result.push(&values[segment_start..index]);
+      segment_start = index;
+    }
+  }
+
+  result
+}
+
+fn condition_window(values: &Values, condition_start: u64) -> Values {
+  values
+    .iter()
+    .filter(|(timestamp, _)| *timestamp >= condition_start)
+    .cloned()
+    .collect()
+}
+
+fn threshold_polygons(
+  condition: &Condition,
+  value: f32,
+  start: DateTime<Utc>,
+  end: DateTime<Utc>,
+  min: f32,
+  max: f32,
+) -> Vec<Vec<(DateTime<Utc>, f32)>> {
+  match *condition {
+    Condition::Less => vec![vec![(start, min), (end, min), (end, value), (start, value)]],
+    Condition::Greater => vec![vec![(start, value), (end, value), (end, max), (start, max)]],
+    Condition::Outside { low, high, .. } => vec![
+      vec![(start, min), (end, min), (end, low), (start, low)],
+      vec![(start, high), (end, high), (end, max), (start, max)],
+    ],
+    Condition::Inside { low, high, .. } => vec![vec![(start, low), (end, low), (end, high), (start, high)]],
+  }
+}
+
 fn parse_time(t: i64) -> chrono::DateTime<Utc> {
   let naive = chrono::NaiveDateTime::from_timestamp(t, 0);
 
@@ -187,3 +600,31 @@ fn parse_time(t: i64) -> chrono::DateTime<Utc> {
 fn label_color(index: usize) -> RGBColor {
   COLORS[index % 18]
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn split_at_missing_samples() {
+    let values = vec![(0, 1.0), (10, 2.0), (20, 3.0), (40, 4.0), (50, 5.0)];
+
+    assert_eq!(vec![&values[0..3], &values[3..5]], split_gaps(&values, 10));
+  }
+
+  #[test]
+  fn split_by_spacing_of_sparse_samples() {
+    // Graphite series with retention of a minute, requested with smaller step
+    let values = vec![(0, 1.0), (60, 2.0), (120, 3.0), (240, 4.0)];
+
+    assert_eq!(vec![&values[0..3], &values[3..4]], split_gaps(&values, 10));
+  }
+
+  #[test]
+  fn split_without_gaps() {
+    let values = vec![(0, 1.0)];
+
+    assert_eq!(vec![&values[0..1]], split_gaps(&values, 10));
+    assert!(split_gaps(&[], 10).is_empty());
+  }
+}
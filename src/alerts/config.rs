@@ -1,34 +1,232 @@
+use crate::alerts::datasource::{self, Datasource};
+use crate::alerts::expression::{self, Expr};
 use json::JsonValue;
-use reqwest::header::HeaderMap;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION};
+use reqwest::{Certificate, Identity, Url};
 use serde_derive::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::fmt::{Display, Formatter};
+use std::fmt::{Debug, Display, Formatter};
 use std::fs::File;
+use std::net::{IpAddr, SocketAddr};
 use std::ops::Add;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct Config {
   #[serde(default)]
   pub repeat_interval_secs: u64,
 
+  // Repeat interval of firing critical labels, `repeat_interval_secs` is used if not set
+  #[serde(default)]
+  pub critical_repeat_interval_secs: u64,
+
   #[serde(default)]
   pub no_data_message: String,
 
+  // Labels missing from storage for this long are forgotten, 0 to keep them
+  #[serde(default = "Config::default_no_data_retention_secs")]
+  pub no_data_retention_secs: u64,
+
+  // Alerts evaluated at the same time, queries are limited by `max_concurrent_queries` of datasource
+  #[serde(default = "Config::default_max_concurrent_alerts")]
+  pub max_concurrent_alerts: usize,
+
   #[serde(default)]
-  pub datasources: HashMap<String, Datasource>,
+  pub datasources: HashMap<String, DatasourceConfig>,
 
   #[serde(default)]
   pub alerts: Vec<Alert>,
 }
 
+impl Config {
+  pub fn critical_repeat_interval(&self) -> u64 {
+    if self.critical_repeat_interval_secs == 0 {
+      self.repeat_interval_secs
+    } else {
+      self.critical_repeat_interval_secs
+    }
+  }
+
+  fn default_no_data_retention_secs() -> u64 {
+    24 * 60 * 60
+  }
+
+  fn default_max_concurrent_alerts() -> usize {
+    4
+  }
+}
+
 pub fn init_config(_: &crate::config::Config) -> anyhow::Result<Config> {
-  let config = read_config()?;
+  let mut config = read_config()?;
+
+  for alert in &mut config.alerts {
+    if let Some(condition) = &alert.condition_config {
+      alert.condition = condition.clone();
+    }
+
+    if let AlertCondition::Expression { expression, parsed } = &mut alert.condition {
+      match expression::parse(expression) {
+        Ok(expr) => *parsed = Some(expr),
+        Err(err) => return Err(anyhow::anyhow!("Invalid expression of {}: {}", alert.name, err)),
+      }
+    }
+  }
+
+  // Clients are shared by all requests to datasource, invalid headers and TLS files fail here
+  for (name, datasource) in &mut config.datasources {
+    match datasource.build_client() {
+      Ok(client) => datasource.client = Some(client),
+      Err(err) => return Err(anyhow::anyhow!("Invalid datasource {}: {}", name, err)),
+    }
+
+    if datasource.max_concurrent_queries > 0 {
+      datasource.queries = Some(Arc::new(Semaphore::new(datasource.max_concurrent_queries)));
+    }
+  }
 
   // Validate
+  for (name, datasource) in &config.datasources {
+    if let DatasourceAuth::BearerFile(path) = &datasource.auth {
+      if let Err(err) = std::fs::read_to_string(path) {
+        return Err(anyhow::anyhow!("Could not read token of datasource {}: {}", name, err));
+      }
+    }
+  }
+
   for alert in &config.alerts {
+    if let Some(composite) = &alert.composite {
+      for name in composite.alert_names() {
+        match config.alerts.iter().find(|a| a.name == name) {
+          None => {
+            return Err(anyhow::anyhow!(
+              "Could not find alert {} for composite {}",
+              name,
+              alert.name
+            ))
+          }
+          // Composites are evaluated after other alerts, so they can't depend on each other
+          Some(referenced) if referenced.composite.is_some() => {
+            return Err(anyhow::anyhow!(
+              "Composite {} can't reference composite {}",
+              alert.name,
+              name
+            ))
+          }
+          Some(_) => {}
+        }
+      }
+
+      continue;
+    }
+
+    if alert.condition_config.is_none() {
+      return Err(anyhow::anyhow!("Condition should be set for {}", alert.name));
+    }
+
+    if alert.condition_range_s == 0 || alert.graph_range_s == 0 {
+      return Err(anyhow::anyhow!(
+        "condition_range_s and graph_range_s of {} should be positive",
+        alert.name
+      ));
+    }
+
+    if alert.label.is_empty() {
+      return Err(anyhow::anyhow!("Label should be set for {}", alert.name));
+    }
+
+    if alert.query.is_empty() != alert.is_ratio() {
+      return Err(anyhow::anyhow!(
+        "Either query or numerator_query with denominator_query should be set for {}",
+        alert.name
+      ));
+    }
+
     if !config.datasources.contains_key(&alert.datasource) {
       return Err(anyhow::anyhow!("Could not find datasource {}", alert.datasource));
     }
+
+    if alert.mode == EvaluationMode::Instant {
+      if let AlertCondition::Change { .. }
+      | AlertCondition::DutyCycle { .. }
+      | AlertCondition::Forecast { .. }
+      | AlertCondition::Anomaly { .. } = alert.condition
+      {
+        return Err(anyhow::anyhow!(
+          "Condition of {} needs range of samples and can't be used in instant mode",
+          alert.name
+        ));
+      }
+    }
+
+    if alert.step_s().is_none() {
+      return Err(anyhow::anyhow!("Invalid step {} of {}", alert.step, alert.name));
+    }
+
+    if let Some(aggregate) = &alert.aggregate {
+      if aggregate.min_count == 0 && aggregate.min_percent <= 0.0 {
+        return Err(anyhow::anyhow!(
+          "Aggregate of {} needs min_count or min_percent",
+          alert.name
+        ));
+      }
+    }
+
+    if let AlertCondition::Percentile { quantile, .. } = alert.condition {
+      if !(0.0..=1.0).contains(&quantile) {
+        return Err(anyhow::anyhow!("Quantile of {} should be between 0 and 1", alert.name));
+      }
+    }
+
+    if let AlertCondition::Anomaly { baseline_range_s, .. } = alert.condition {
+      if baseline_range_s <= alert.condition_range_s {
+        return Err(anyhow::anyhow!(
+          "Baseline range of {} should be longer than condition range",
+          alert.name
+        ));
+      }
+    }
+
+    if !alert.tiers.is_empty() {
+      // Bands and expressions have no value to compare with, forecast estimates crossing of condition value
+      if matches!(
+        alert.condition.threshold().0,
+        Condition::Outside { .. } | Condition::Inside { .. }
+      ) || matches!(
+        alert.condition,
+        AlertCondition::Expression { .. } | AlertCondition::Forecast { .. }
+      ) {
+        return Err(anyhow::anyhow!(
+          "Tiers of {} can't be used with bands, expressions and forecasts",
+          alert.name
+        ));
+      }
+
+      let mut severities = alert.tiers.iter().map(|tier| tier.severity).collect::<Vec<_>>();
+      severities.sort();
+      severities.dedup();
+      if severities.len() != alert.tiers.len() {
+        return Err(anyhow::anyhow!(
+          "Severities of tiers of {} should be unique",
+          alert.name
+        ));
+      }
+    }
+
+    if let (Condition::Less | Condition::Greater, None, _) = alert.condition.configured_threshold() {
+      if alert.tiers.is_empty() {
+        return Err(anyhow::anyhow!("Condition of {} needs value or tiers", alert.name));
+      }
+    }
+
+    if let Condition::Outside { low, high, .. } | Condition::Inside { low, high, .. } = alert.condition.threshold().0 {
+      if low > high {
+        return Err(anyhow::anyhow!(
+          "Low bound of {} should not be greater than high",
+          alert.name
+        ));
+      }
+    }
   }
 
   Ok(config)
@@ -58,18 +256,83 @@ fn read_config() -> anyhow::Result<Config> {
 // Datasources
 ////
 
-#[derive(Debug, Default, Clone, Serialize, Deserialize)]
-pub struct Datasource {
+#[derive(Default, Clone, Serialize, Deserialize)]
+pub struct DatasourceConfig {
+  // API of storage, implementations are in alerts::datasource
+  #[serde(rename = "type", default)]
+  pub kind: DatasourceType,
+
   url: String,
 
   #[serde(default = "DatasourceAuth::default")]
   auth: DatasourceAuth,
+
+  // Sent with every request, like X-Scope-OrgID of multitenant storage
+  #[serde(default)]
+  headers: HashMap<String, String>,
+
+  #[serde(default)]
+  tls: Option<DatasourceTls>,
+
+  // Timeout of single request, 0 to wait indefinitely
+  #[serde(default = "DatasourceConfig::default_timeout_s")]
+  timeout_s: u64,
+  // Failed requests are retried with backoff doubled after each attempt
+  #[serde(default)]
+  retries: u32,
+  #[serde(default = "DatasourceConfig::default_retry_backoff_ms")]
+  retry_backoff_ms: u64,
+
+  // Queries are sent as form-encoded POST, for queries too long for url
+  #[serde(default)]
+  post: bool,
+
+  // Requests over this number wait for running ones to finish, 0 for no limit
+  #[serde(default = "DatasourceConfig::default_max_concurrent_queries")]
+  max_concurrent_queries: usize,
+
+  #[serde(skip)]
+  client: Option<reqwest::Client>,
+  #[serde(skip)]
+  queries: Option<Arc<Semaphore>>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DatasourceType {
+  // Prometheus HTTP API, also served by VictoriaMetrics
+  #[default]
+  Prometheus,
+  // Render API, query of alert is a target, like `servers.*.cpu.load`
+  Graphite,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 enum DatasourceAuth {
   None,
   AuthorizationHeader(String),
+  Basic { username: String, password: String },
+  Bearer(String),
+  // Path to file with token, it is read on every request so rotated tokens are picked up
+  BearerFile(String),
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct DatasourceTls {
+  // PEM bundle of CAs, used instead of built-in roots
+  #[serde(default)]
+  pub ca_file: String,
+  // PEM client certificate and its private key for mutual TLS
+  #[serde(default)]
+  pub cert_file: String,
+  #[serde(default)]
+  pub key_file: String,
+  // Certificate of server is verified against this name instead of IP address from url.
+  // Requests are sent to this name, so it is also used in Host header
+  #[serde(default)]
+  pub server_name: String,
+  #[serde(default)]
+  pub insecure_skip_verify: bool,
 }
 
 impl Default for DatasourceAuth {
@@ -78,21 +341,188 @@ impl Default for DatasourceAuth {
   }
 }
 
-impl Datasource {
-  pub async fn fetch(&self, url: String) -> anyhow::Result<JsonValue> {
-    let mut headers = HeaderMap::new();
-    match self.auth.clone() {
-      DatasourceAuth::None => {}
-      DatasourceAuth::AuthorizationHeader(value) => {
-        headers.insert("Authorization", value.parse().unwrap());
+impl DatasourceConfig {
+  /// Requests `path` with encoded `params`, in url or in body if datasource uses POST
+  pub async fn fetch(&self, path: &str, params: &[(String, String)]) -> anyhow::Result<JsonValue> {
+    let mut attempt = 0;
+    loop {
+      match self.fetch_once(path, params).await {
+        Err(err) if attempt < self.retries => {
+          let backoff = self.retry_backoff_ms.saturating_mul(2u64.saturating_pow(attempt));
+          log::warn!("Request to {} failed, retrying in {}ms: {}", self.url, backoff, err);
+
+          tokio::time::sleep(std::time::Duration::from_millis(backoff)).await;
+          attempt += 1;
+        }
+        result => return result,
       }
     }
+  }
+
+  async fn fetch_once(&self, path: &str, params: &[(String, String)]) -> anyhow::Result<JsonValue> {
+    // Permit is held until response is read
+    let _permit = match &self.queries {
+      Some(queries) => Some(queries.acquire().await?),
+      None => None,
+    };
 
-    let client = reqwest::Client::builder().default_headers(headers).build()?;
-    let response = client.get(self.url.clone().add("/").add(&url)).send().await?;
+    // Client is built in init_config
+    let client = match &self.client {
+      Some(client) => client.clone(),
+      None => self.build_client()?,
+    };
+    let url = self.base_url()?.add("/").add(path);
+    let mut request = if self.post {
+      client.post(url).form(params)
+    } else {
+      client.get(url).query(params)
+    };
+
+    request = match &self.auth {
+      DatasourceAuth::None => request,
+      DatasourceAuth::AuthorizationHeader(value) => {
+        // Same as basic and bearer auth, sensitive headers are not printed by reqwest
+        let mut value = HeaderValue::from_str(value)?;
+        value.set_sensitive(true);
+        request.header(AUTHORIZATION, value)
+      }
+      DatasourceAuth::Basic { username, password } => request.basic_auth(username, Some(password)),
+      DatasourceAuth::Bearer(token) => request.bearer_auth(token),
+      DatasourceAuth::BearerFile(path) => request.bearer_auth(tokio::fs::read_to_string(path).await?.trim()),
+    };
+    let response = request.send().await?;
+    if response.status().is_server_error() {
+      return Err(anyhow::anyhow!("Datasource responded with {}", response.status()));
+    }
 
     Ok(json::parse(response.text().await?.as_str())?)
   }
+
+  fn build_client(&self) -> anyhow::Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder().default_headers(self.headers()?);
+    if self.timeout_s > 0 {
+      builder = builder.timeout(std::time::Duration::from_secs(self.timeout_s));
+    }
+    if let Some(tls) = &self.tls {
+      builder = builder
+        .use_rustls_tls()
+        .danger_accept_invalid_certs(tls.insecure_skip_verify);
+
+      if !tls.ca_file.is_empty() {
+        let bundle = read_file(&tls.ca_file)?;
+        if !String::from_utf8_lossy(&bundle).contains("-----BEGIN CERTIFICATE-----") {
+          return Err(anyhow::anyhow!("No certificates in CA bundle {}", tls.ca_file));
+        }
+
+        builder = builder
+          .tls_built_in_root_certs(false)
+          .add_root_certificate(Certificate::from_pem(&bundle)?);
+      }
+
+      if !tls.cert_file.is_empty() || !tls.key_file.is_empty() {
+        let mut pem = read_file(&tls.cert_file)?;
+        pem.extend(read_file(&tls.key_file)?);
+        builder = builder.identity(Identity::from_pem(&pem)?);
+      }
+
+      // Requests go to `server_name`, which is resolved to IP address from url
+      if !tls.server_name.is_empty() {
+        builder = builder.resolve(&tls.server_name, self.address()?);
+      }
+    }
+
+    Ok(builder.build()?)
+  }
+
+  /// Url with host replaced by `server_name`, so certificate is verified against it even if url has IP address
+  fn base_url(&self) -> anyhow::Result<String> {
+    let server_name = match &self.tls {
+      Some(tls) if !tls.server_name.is_empty() => &tls.server_name,
+      _ => return Ok(self.url.clone()),
+    };
+
+    let mut url = Url::parse(&self.url)?;
+    url.set_host(Some(server_name))?;
+
+    Ok(url.as_str().trim_end_matches('/').to_owned())
+  }
+
+  /// Address from url, `server_name` is only used with IP address, domains are resolved by each request as usual
+  fn address(&self) -> anyhow::Result<SocketAddr> {
+    let url = Url::parse(&self.url)?;
+    let port = url.port_or_known_default().unwrap_or(443);
+    // IPv6 address is in brackets in url
+    let host = url
+      .host_str()
+      .unwrap_or("")
+      .trim_start_matches('[')
+      .trim_end_matches(']');
+
+    match host.parse::<IpAddr>() {
+      Ok(ip) => Ok(SocketAddr::new(ip, port)),
+      Err(_) => Err(anyhow::anyhow!(
+        "server_name can only be used with IP address in url, not {}",
+        host
+      )),
+    }
+  }
+
+  fn headers(&self) -> anyhow::Result<HeaderMap> {
+    let mut headers = HeaderMap::new();
+    for (name, value) in &self.headers {
+      headers.insert(HeaderName::from_bytes(name.as_bytes())?, value.parse()?);
+    }
+
+    Ok(headers)
+  }
+
+  fn default_timeout_s() -> u64 {
+    30
+  }
+  fn default_retry_backoff_ms() -> u64 {
+    500
+  }
+  fn default_max_concurrent_queries() -> usize {
+    4
+  }
+}
+
+fn read_file(path: &str) -> anyhow::Result<Vec<u8>> {
+  std::fs::read(path).map_err(|err| anyhow::anyhow!("Could not read {}: {}", path, err))
+}
+
+// Credentials and header values are hidden, config is printed in debug logs
+impl Debug for DatasourceConfig {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("DatasourceConfig")
+      .field("kind", &self.kind)
+      .field("url", &self.url)
+      .field("auth", &self.auth)
+      .field("headers", &self.headers.keys().collect::<Vec<_>>())
+      .field("tls", &self.tls)
+      .field("timeout_s", &self.timeout_s)
+      .field("retries", &self.retries)
+      .field("retry_backoff_ms", &self.retry_backoff_ms)
+      .field("post", &self.post)
+      .field("max_concurrent_queries", &self.max_concurrent_queries)
+      .finish()
+  }
+}
+
+impl Debug for DatasourceAuth {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match self {
+      DatasourceAuth::None => write!(f, "None"),
+      DatasourceAuth::AuthorizationHeader(_) => write!(f, "AuthorizationHeader(***)"),
+      DatasourceAuth::Basic { username, .. } => f
+        .debug_struct("Basic")
+        .field("username", username)
+        .field("password", &"***")
+        .finish(),
+      DatasourceAuth::Bearer(_) => write!(f, "Bearer(***)"),
+      DatasourceAuth::BearerFile(path) => f.debug_tuple("BearerFile").field(path).finish(),
+    }
+  }
 }
 
 ////
@@ -102,23 +532,66 @@ impl Datasource {
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct Alert {
   pub name: String,
+  #[serde(default)]
   pub datasource: String,
 
   // Call alert once in `interval` times
   #[serde(default)]
   pub interval_s: u32,
 
+  // Combines statuses of other alerts, used instead of query and condition
+  #[serde(default)]
+  pub composite: Option<Composite>,
+
+  #[serde(default)]
   pub query: String,
+  // Condition is checked on ratio of these queries, used instead of query
+  #[serde(default)]
+  pub numerator_query: String,
+  #[serde(default)]
+  pub denominator_query: String,
+  // Instant evaluates query once at evaluation time, charts always use range
+  #[serde(default)]
+  pub mode: EvaluationMode,
+  // Required for all alerts except composite, moved to `condition` by init_config
+  #[serde(default, rename = "condition")]
+  pub condition_config: Option<AlertCondition>,
+  #[serde(skip)]
   pub condition: AlertCondition,
+  #[serde(default)]
   pub condition_range_s: u64,
+  // Thresholds by severity, replace `value` and `value_ok` of condition
+  #[serde(default)]
+  pub tiers: Vec<ThresholdTier>,
+  // Fire single status for the whole alert when enough labels are breaching
+  #[serde(default)]
+  pub aggregate: Option<Aggregate>,
+  // Label stays pending until condition is breached for this long
+  #[serde(default)]
+  pub for_s: u64,
+  // Firing label resolves only after condition is not breached for this long
+  #[serde(default)]
+  pub keep_firing_for_s: u64,
+  // Labels with fewer valid samples in range are marked as no data
+  #[serde(default)]
+  pub min_samples: usize,
+  #[serde(default)]
   pub graph_range_s: u64,
   #[serde(default = "Alert::default_graph_min")]
   pub graph_min: f32,
   #[serde(default = "Alert::default_graph_max")]
   pub graph_max: f32,
 
+  // Draw numerator and denominator of ratio on secondary axis
+  #[serde(default)]
+  pub graph_raw_series: bool,
+
   #[serde(default = "Alert::default_step")]
   pub step: String,
+  // Added to every query of alert, like extra_label, nocache or timeout
+  #[serde(default)]
+  pub params: HashMap<String, String>,
+  #[serde(default)]
   pub label: String,
 
   pub description: String,
@@ -126,34 +599,369 @@ pub struct Alert {
   pub statuses: HashMap<AlertStatus, String>,
 }
 
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EvaluationMode {
+  // Samples over condition range by step, /api/v1/query_range
+  #[default]
+  Range,
+  // Single sample per label, /api/v1/query
+  Instant,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Composite {
+  // Label is firing in alert with this name
+  Alert(String),
+  And(Vec<Composite>),
+  Or(Vec<Composite>),
+  Not(Box<Composite>),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Aggregate {
+  // Fires when at least this number of labels is breaching
+  #[serde(default)]
+  pub min_count: usize,
+  // Or at least this percentage (0 - 100) of labels
+  #[serde(default)]
+  pub min_percent: f32,
+  // Label of the alert-level status
+  #[serde(default = "Aggregate::default_label")]
+  pub label: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ThresholdTier {
+  pub severity: Severity,
+  pub value: f32,
+  pub value_ok: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Severity {
+  Warning,
+  Critical,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Condition {
   Less,
   Greater,
+  // Fires when value leaves [low, high], resolves when it is back in [low + margin, high - margin]
+  Outside {
+    low: f32,
+    high: f32,
+    #[serde(default)]
+    margin: f32,
+  },
+  // Fires when value enters [low, high], resolves when it is out of [low - margin, high + margin]
+  Inside {
+    low: f32,
+    high: f32,
+    #[serde(default)]
+    margin: f32,
+  },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum AlertCondition {
+  // `value_ok` defaults to `value`, both can be omitted by bands and alerts with tiers
   Avg {
     condition: Condition,
+    value: Option<f32>,
+    value_ok: Option<f32>,
+  },
+  Max {
+    condition: Condition,
+    value: Option<f32>,
+    value_ok: Option<f32>,
+  },
+  Min {
+    condition: Condition,
+    value: Option<f32>,
+    value_ok: Option<f32>,
+  },
+  Last {
+    condition: Condition,
+    value: Option<f32>,
+    value_ok: Option<f32>,
+  },
+  Sum {
+    condition: Condition,
+    value: Option<f32>,
+    value_ok: Option<f32>,
+  },
+  Count {
+    condition: Condition,
+    value: Option<f32>,
+    value_ok: Option<f32>,
+  },
+  Percentile {
+    // 0.0 - 1.0, same as in `quantile_over_time`
+    quantile: f32,
+    condition: Condition,
+    value: Option<f32>,
+    value_ok: Option<f32>,
+  },
+  Change {
+    method: ChangeMethod,
+    condition: Condition,
+    value: Option<f32>,
+    value_ok: Option<f32>,
+  },
+  // Percentage of samples matching `condition` with `threshold`, fires when it is greater than `value`
+  DutyCycle {
+    condition: Condition,
+    threshold: f32,
     value: f32,
     value_ok: f32,
   },
+  // Boolean expression over reducers of condition range, like `avg > 90 && max > 99`
+  Expression {
+    expression: String,
+    // Parsed in init_config
+    #[serde(skip)]
+    parsed: Option<Expr>,
+  },
+  // Compares average of condition range with the same range `offset_s` ago
+  Compare {
+    offset_s: u64,
+    method: CompareMethod,
+    condition: Condition,
+    value: Option<f32>,
+    value_ok: Option<f32>,
+  },
+  // Linear regression of condition range, checks value projected `horizon_s` ahead
+  Forecast {
+    horizon_s: u64,
+    condition: Condition,
+    value: Option<f32>,
+    value_ok: Option<f32>,
+  },
+  // Compares average of condition range with samples before it, values are in standard deviations (z-score)
+  Anomaly {
+    baseline_range_s: u64,
+    // Baseline with smaller standard deviation gives no verdict, flat baseline never gives one
+    #[serde(default)]
+    min_std: f32,
+    condition: Condition,
+    value: Option<f32>,
+    value_ok: Option<f32>,
+  },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ChangeMethod {
+  // Difference between last and first samples
+  Delta,
+  // Delta divided by seconds between last and first samples
+  Slope,
+  // Largest change between two consecutive samples, in direction of condition
+  MaxStep,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum CompareMethod {
+  // Current minus previous
+  Absolute,
+  // Difference in percents of previous
+  Relative,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub enum AlertStatus {
   Ok,
+  Pending,
+  Warning,
   Err,
   NoData,
+  // Storage could not be queried, error of the first failed evaluation is kept until it recovers
+  DatasourceError(String),
 }
 
 impl Default for AlertCondition {
   fn default() -> AlertCondition {
     AlertCondition::Avg {
       condition: Condition::Less,
-      value: 0.0,
-      value_ok: 0.0,
+      value: None,
+      value_ok: None,
+    }
+  }
+}
+
+impl Aggregate {
+  pub fn is_breached(&self, breaching: usize, total: usize) -> bool {
+    let percent = breaching as f32 / total as f32 * 100.0;
+
+    (self.min_count > 0 && breaching >= self.min_count) || (self.min_percent > 0.0 && percent >= self.min_percent)
+  }
+
+  fn default_label() -> String {
+    "fleet".to_owned()
+  }
+}
+
+impl Composite {
+  pub fn alert_names(&self) -> Vec<String> {
+    match self {
+      Composite::Alert(name) => vec![name.clone()],
+      Composite::And(items) | Composite::Or(items) => items.iter().flat_map(|item| item.alert_names()).collect(),
+      Composite::Not(item) => item.alert_names(),
+    }
+  }
+
+  /// `statuses` are label statuses of referenced alerts by their names
+  pub fn evaluate(&self, label: &str, statuses: &HashMap<String, HashMap<String, AlertStatus>>) -> bool {
+    match self {
+      Composite::Alert(name) => matches!(
+        statuses.get(name).and_then(|statuses| statuses.get(label)),
+        Some(status) if status.is_firing()
+      ),
+      Composite::And(items) => items.iter().all(|item| item.evaluate(label, statuses)),
+      Composite::Or(items) => items.iter().any(|item| item.evaluate(label, statuses)),
+      Composite::Not(item) => !item.evaluate(label, statuses),
+    }
+  }
+}
+
+impl Condition {
+  /// `threshold` is used by Less and Greater, band conditions apply their margin when `firing`
+  pub fn matches(&self, value: f32, threshold: f32, firing: bool) -> bool {
+    match self {
+      Condition::Less => value < threshold,
+      Condition::Greater => value > threshold,
+      Condition::Outside { low, high, margin } => {
+        let margin = if firing { *margin } else { 0.0 };
+
+        value < low + margin || value > high - margin
+      }
+      Condition::Inside { low, high, margin } => {
+        let margin = if firing { *margin } else { 0.0 };
+
+        value >= low - margin && value <= high + margin
+      }
+    }
+  }
+}
+
+impl Condition {
+  /// Values at which condition starts to match
+  pub fn bounds(&self, threshold: f32) -> Vec<f32> {
+    match *self {
+      Condition::Less | Condition::Greater => vec![threshold],
+      Condition::Outside { low, high, .. } | Condition::Inside { low, high, .. } => vec![low, high],
+    }
+  }
+}
+
+impl AlertCondition {
+  /// Returns (condition, value, value_ok) of the condition, `value_ok` defaults to `value`.
+  /// Value is only missing for bands and alerts with tiers, see init_config
+  pub fn threshold(&self) -> (&Condition, f32, f32) {
+    let (condition, value, value_ok) = self.configured_threshold();
+
+    (condition, value.unwrap_or(0.0), value_ok.or(value).unwrap_or(0.0))
+  }
+
+  fn configured_threshold(&self) -> (&Condition, Option<f32>, Option<f32>) {
+    match self {
+      AlertCondition::Avg {
+        condition,
+        value,
+        value_ok,
+      }
+      | AlertCondition::Max {
+        condition,
+        value,
+        value_ok,
+      }
+      | AlertCondition::Min {
+        condition,
+        value,
+        value_ok,
+      }
+      | AlertCondition::Last {
+        condition,
+        value,
+        value_ok,
+      }
+      | AlertCondition::Sum {
+        condition,
+        value,
+        value_ok,
+      }
+      | AlertCondition::Count {
+        condition,
+        value,
+        value_ok,
+      }
+      | AlertCondition::Percentile {
+        condition,
+        value,
+        value_ok,
+        ..
+      }
+      | AlertCondition::Change {
+        condition,
+        value,
+        value_ok,
+        ..
+      }
+      | AlertCondition::Compare {
+        condition,
+        value,
+        value_ok,
+        ..
+      }
+      | AlertCondition::Forecast {
+        condition,
+        value,
+        value_ok,
+        ..
+      }
+      | AlertCondition::Anomaly {
+        condition,
+        value,
+        value_ok,
+        ..
+      } => (condition, *value, *value_ok),
+      AlertCondition::DutyCycle { value, value_ok, .. } => (&Condition::Greater, Some(*value), Some(*value_ok)),
+      // Expression is evaluated to 1.0 or 0.0
+      AlertCondition::Expression { .. } => (&Condition::Greater, Some(0.5), Some(0.5)),
+    }
+  }
+
+  pub fn expression(&self) -> Option<&Expr> {
+    match self {
+      AlertCondition::Expression { parsed, .. } => parsed.as_ref(),
+      _ => None,
+    }
+  }
+
+  /// Short name of reduced value, shown next to labels
+  pub fn reducer_name(&self) -> String {
+    match self {
+      AlertCondition::Avg { .. } => "avg".to_owned(),
+      AlertCondition::Max { .. } => "max".to_owned(),
+      AlertCondition::Min { .. } => "min".to_owned(),
+      AlertCondition::Last { .. } => "last".to_owned(),
+      AlertCondition::Sum { .. } => "sum".to_owned(),
+      AlertCondition::Count { .. } => "count".to_owned(),
+      AlertCondition::Percentile { quantile, .. } => format!("p{}", (quantile * 1000.0).round() / 10.0),
+      AlertCondition::Change { method, .. } => match method {
+        ChangeMethod::Delta => "delta".to_owned(),
+        ChangeMethod::Slope => "slope".to_owned(),
+        ChangeMethod::MaxStep => "max step".to_owned(),
+      },
+      AlertCondition::DutyCycle { .. } => "duty cycle %".to_owned(),
+      AlertCondition::Expression { expression, .. } => expression.clone(),
+      AlertCondition::Compare { method, .. } => match method {
+        CompareMethod::Absolute => "difference".to_owned(),
+        CompareMethod::Relative => "difference %".to_owned(),
+      },
+      AlertCondition::Forecast { .. } => "forecast".to_owned(),
+      AlertCondition::Anomaly { .. } => "z-score".to_owned(),
     }
   }
 }
@@ -170,9 +978,77 @@ impl Alert {
     label
   }
 
-  pub fn datasource_instance(&self) -> Datasource {
+  /// Returns (severity, value, value_ok) of tiers, most severe first
+  pub fn thresholds(&self) -> Vec<(Severity, f32, f32)> {
+    if self.tiers.is_empty() {
+      let (_, value, value_ok) = self.condition.threshold();
+
+      return vec![(Severity::Critical, value, value_ok)];
+    }
+
+    let mut thresholds: Vec<_> = self
+      .tiers
+      .iter()
+      .map(|tier| (tier.severity, tier.value, tier.value_ok))
+      .collect();
+    thresholds.sort_by_key(|threshold| std::cmp::Reverse(threshold.0));
+
+    thresholds
+  }
+
+  /// Status of the most severe breached tier, `value_ok` is used for tiers label was already firing at
+  pub fn breached_status(&self, reduced: f32, old_status: &AlertStatus) -> Option<AlertStatus> {
+    let (condition, _, _) = self.condition.threshold();
+
+    self
+      .thresholds()
+      .into_iter()
+      .find(|(severity, value, value_ok)| {
+        // Labels without verdict, like missing or failed to request, are checked against value
+        let firing = matches!(old_status.severity(), Some(old) if old >= *severity);
+
+        condition.matches(reduced, if firing { *value_ok } else { *value }, firing)
+      })
+      .map(|(severity, _, _)| severity.status())
+  }
+
+  pub fn is_ratio(&self) -> bool {
+    !self.numerator_query.is_empty() && !self.denominator_query.is_empty()
+  }
+
+  /// Range of samples requested for condition
+  pub fn evaluation_range_s(&self) -> u64 {
+    match self.condition {
+      AlertCondition::Anomaly { baseline_range_s, .. } => baseline_range_s,
+      _ => self.condition_range_s,
+    }
+  }
+
+  /// Step between samples in seconds, validated in init_config
+  pub fn step_s(&self) -> Option<u64> {
+    crate::util::parse_duration_s(&self.step)
+  }
+
+  /// Shift of previous period for Compare condition
+  pub fn compare_offset_s(&self) -> Option<u64> {
+    match self.condition {
+      AlertCondition::Compare { offset_s, .. } => Some(offset_s),
+      _ => None,
+    }
+  }
+
+  /// Parameters of request with query, followed by `params` of alert
+  pub fn query_params(&self, params: Vec<(&str, String)>) -> Vec<(String, String)> {
+    params
+      .into_iter()
+      .map(|(name, value)| (name.to_owned(), value))
+      .chain(self.params.iter().map(|(name, value)| (name.clone(), value.clone())))
+      .collect()
+  }
+
+  pub fn datasource_instance(&self) -> Box<dyn Datasource> {
     // Datasource is validated in init_config, safe to unwrap
-    crate::CONFIG.alerts.datasources.get(&self.datasource).unwrap().clone()
+    datasource::create(crate::CONFIG.alerts.datasources.get(&self.datasource).unwrap())
   }
 
   pub fn status_description(&self, status: &AlertStatus) -> String {
@@ -195,11 +1071,35 @@ impl Alert {
 }
 
 impl AlertStatus {
+  pub fn is_firing(&self) -> bool {
+    matches!(self, AlertStatus::Warning | AlertStatus::Err)
+  }
+
+  pub fn severity(&self) -> Option<Severity> {
+    match self {
+      AlertStatus::Warning => Some(Severity::Warning),
+      AlertStatus::Err => Some(Severity::Critical),
+      _ => None,
+    }
+  }
+
   pub fn emoji(&self) -> &'static str {
     match self {
       AlertStatus::Ok => "✅",
+      AlertStatus::Pending => "⏳",
+      AlertStatus::Warning => "🔶",
       AlertStatus::Err => "‼",
       AlertStatus::NoData => "️⚠️",
+      AlertStatus::DatasourceError(_) => "🔌",
+    }
+  }
+}
+
+impl Severity {
+  pub fn status(&self) -> AlertStatus {
+    match self {
+      Severity::Warning => AlertStatus::Warning,
+      Severity::Critical => AlertStatus::Err,
     }
   }
 }
@@ -216,12 +1116,175 @@ impl Display for AlertStatus {
       AlertStatus::Ok => {
         write!(f, "Ok")
       }
+      AlertStatus::Pending => {
+        write!(f, "Pending")
+      }
+      AlertStatus::Warning => {
+        write!(f, "Warning")
+      }
       AlertStatus::Err => {
         write!(f, "Firing")
       }
       AlertStatus::NoData => {
         write!(f, "No data")
       }
+      AlertStatus::DatasourceError(err) => {
+        write!(f, "Datasource error: {}", err)
+      }
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn outside_band_resolves_inside_margin() {
+    let condition = Condition::Outside {
+      low: 10.0,
+      high: 20.0,
+      margin: 2.0,
+    };
+
+    assert!(condition.matches(9.0, 0.0, false));
+    assert!(condition.matches(21.0, 0.0, false));
+    assert!(!condition.matches(11.0, 0.0, false));
+
+    // Firing label resolves only within [low + margin, high - margin]
+    assert!(condition.matches(11.0, 0.0, true));
+    assert!(condition.matches(19.0, 0.0, true));
+    assert!(!condition.matches(15.0, 0.0, true));
+  }
+
+  #[test]
+  fn inside_band_resolves_outside_margin() {
+    let condition = Condition::Inside {
+      low: 10.0,
+      high: 20.0,
+      margin: 2.0,
+    };
+
+    assert!(condition.matches(10.0, 0.0, false));
+    assert!(!condition.matches(9.0, 0.0, false));
+
+    // Firing label resolves only outside of [low - margin, high + margin]
+    assert!(condition.matches(9.0, 0.0, true));
+    assert!(condition.matches(22.0, 0.0, true));
+    assert!(!condition.matches(23.0, 0.0, true));
+  }
+
+  #[test]
+  fn value_ok_defaults_to_value() {
+    let condition: AlertCondition = serde_yaml::from_str("Avg: { condition: Greater, value: 90 }").unwrap();
+    assert_eq!((90.0, 90.0), (condition.threshold().1, condition.threshold().2));
+
+    let condition: AlertCondition =
+      serde_yaml::from_str("Avg: { condition: Greater, value: 90, value_ok: 80 }").unwrap();
+    assert_eq!((90.0, 80.0), (condition.threshold().1, condition.threshold().2));
+  }
+
+  fn get_test_statuses() -> HashMap<String, HashMap<String, AlertStatus>> {
+    let mut cpu = HashMap::new();
+    cpu.insert("host1".to_owned(), AlertStatus::Err);
+    cpu.insert("host2".to_owned(), AlertStatus::Ok);
+    cpu.insert("host3".to_owned(), AlertStatus::Warning);
+
+    let mut memory = HashMap::new();
+    memory.insert("host1".to_owned(), AlertStatus::Warning);
+    memory.insert("host2".to_owned(), AlertStatus::Pending);
+    memory.insert("host3".to_owned(), AlertStatus::Ok);
+
+    let mut statuses = HashMap::new();
+    statuses.insert("cpu".to_owned(), cpu);
+    statuses.insert("memory".to_owned(), memory);
+
+    statuses
+  }
+
+  #[test]
+  fn composite_combines_firing_labels() {
+    let statuses = get_test_statuses();
+    let cpu = || Composite::Alert("cpu".to_owned());
+    let memory = || Composite::Alert("memory".to_owned());
+
+    let both = Composite::And(vec![cpu(), memory()]);
+    assert!(both.evaluate("host1", &statuses));
+    assert!(!both.evaluate("host3", &statuses));
+
+    let any = Composite::Or(vec![cpu(), memory()]);
+    assert!(any.evaluate("host3", &statuses));
+    // Pending label is not firing
+    assert!(!any.evaluate("host2", &statuses));
+
+    let only_cpu = Composite::And(vec![cpu(), Composite::Not(Box::new(memory()))]);
+    assert!(only_cpu.evaluate("host3", &statuses));
+    assert!(!only_cpu.evaluate("host1", &statuses));
+  }
+
+  #[test]
+  fn composite_of_missing_label_or_alert() {
+    let statuses = get_test_statuses();
+
+    assert!(!Composite::Alert("cpu".to_owned()).evaluate("host4", &statuses));
+    assert!(!Composite::Alert("disk".to_owned()).evaluate("host1", &statuses));
+    assert!(Composite::Not(Box::new(Composite::Alert("disk".to_owned()))).evaluate("host1", &statuses));
+  }
+
+  fn aggregate(min_count: usize, min_percent: f32) -> Aggregate {
+    Aggregate {
+      min_count,
+      min_percent,
+      label: Aggregate::default_label(),
+    }
+  }
+
+  #[test]
+  fn aggregate_by_count_or_percent() {
+    assert!(aggregate(3, 0.0).is_breached(3, 10));
+    assert!(!aggregate(3, 0.0).is_breached(2, 10));
+
+    assert!(aggregate(0, 25.0).is_breached(1, 4));
+    assert!(!aggregate(0, 25.0).is_breached(2, 10));
+
+    // Either of limits is enough
+    assert!(aggregate(5, 20.0).is_breached(2, 10));
+    assert!(aggregate(2, 90.0).is_breached(2, 10));
+  }
+
+  #[test]
+  fn aggregate_without_labels() {
+    assert!(!aggregate(0, 10.0).is_breached(0, 0));
+    assert!(!aggregate(1, 0.0).is_breached(0, 0));
+  }
+
+  fn datasource(url: &str, server_name: &str) -> DatasourceConfig {
+    DatasourceConfig {
+      url: url.to_owned(),
+      tls: Some(DatasourceTls {
+        server_name: server_name.to_owned(),
+        ..Default::default()
+      }),
+      ..Default::default()
+    }
+  }
+
+  #[test]
+  fn server_name_replaces_ip_address() {
+    let ipv4 = datasource("https://10.0.0.1:8428", "vm.internal");
+    assert_eq!("https://vm.internal:8428", ipv4.base_url().unwrap());
+    assert_eq!("10.0.0.1:8428", ipv4.address().unwrap().to_string());
+
+    let ipv6 = datasource("https://[::1]/select/0/prometheus", "vm.internal");
+    assert_eq!("https://vm.internal/select/0/prometheus", ipv6.base_url().unwrap());
+    assert_eq!("[::1]:443", ipv6.address().unwrap().to_string());
+  }
+
+  #[test]
+  fn server_name_is_rejected_with_domain() {
+    assert!(datasource("https://vm.example.com", "vm.internal")
+      .build_client()
+      .is_err());
+    assert!(datasource("https://vm.example.com", "").build_client().is_ok());
+  }
+}
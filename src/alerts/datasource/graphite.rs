@@ -0,0 +1,91 @@
+use crate::alerts::config::{Alert, DatasourceConfig};
+use crate::alerts::datasource::Datasource;
+use crate::alerts::Values;
+use async_trait::async_trait;
+use json::JsonValue;
+use std::collections::HashMap;
+
+// Graphite has no instant queries, last sample of this range or of this number of steps is used instead.
+// Step of alert should be set to retention of series coarser than that
+const INSTANT_RANGE_S: i64 = 5 * 60;
+const INSTANT_RANGE_STEPS: i64 = 5;
+
+/// Graphite render API, `/render?format=json`
+pub struct Graphite {
+  config: DatasourceConfig,
+}
+
+impl Graphite {
+  pub fn new(config: DatasourceConfig) -> Graphite {
+    Graphite { config }
+  }
+}
+
+#[async_trait]
+impl Datasource for Graphite {
+  async fn query_range(
+    &self,
+    alert: &Alert,
+    query: &str,
+    start: i64,
+    end: i64,
+  ) -> anyhow::Result<HashMap<String, Values>> {
+    let mut result: HashMap<String, Values> = HashMap::new();
+
+    // Step is defined by retention of metrics, it is not requested
+    let params = alert.query_params(vec![
+      ("target", query.to_owned()),
+      ("from", start.to_string()),
+      ("until", end.to_string()),
+      ("format", "json".to_owned()),
+    ]);
+    let response = self.config.fetch("render", &params).await?;
+
+    for series in response.members() {
+      let mut values = Values::new();
+
+      for datapoint in series["datapoints"].members() {
+        // Missing samples are returned as nulls
+        if let (Some(value), Some(timestamp)) = (datapoint[0].as_f32(), datapoint[1].as_u64()) {
+          values.push((timestamp, value));
+        }
+      }
+
+      result.insert(alert.format_label(&series_labels(series)), values);
+    }
+
+    Ok(result)
+  }
+
+  async fn query_instant(&self, alert: &Alert, query: &str, time: i64) -> anyhow::Result<HashMap<String, Values>> {
+    let range = alert.step_s().map_or(INSTANT_RANGE_S, |step| {
+      INSTANT_RANGE_S.max(step as i64 * INSTANT_RANGE_STEPS)
+    });
+    let mut result = self.query_range(alert, query, time - range, time).await?;
+
+    for values in result.values_mut() {
+      if let Some(last) = values.pop() {
+        *values = vec![last];
+      }
+    }
+
+    Ok(result)
+  }
+}
+
+/// Labels for templating: `{{name}}` is the whole target, `{{0}}`, `{{1}}`, ... are its path segments, tags are used
+/// as is
+fn series_labels(series: &JsonValue) -> JsonValue {
+  let mut labels = JsonValue::new_object();
+  for (key, value) in series["tags"].entries() {
+    labels[key] = value.clone();
+  }
+
+  let name = series["target"].as_str().unwrap_or("");
+  labels["name"] = name.into();
+  for (index, segment) in name.split('.').enumerate() {
+    labels[index.to_string().as_str()] = segment.into();
+  }
+
+  labels
+}
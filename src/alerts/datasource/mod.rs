@@ -0,0 +1,30 @@
+use crate::alerts::config::{Alert, DatasourceConfig, DatasourceType};
+use crate::alerts::Values;
+use async_trait::async_trait;
+use std::collections::HashMap;
+
+mod graphite;
+mod prometheus;
+
+/// Storage of metrics, series are returned by label formatted with `Alert::format_label`
+#[async_trait]
+pub trait Datasource: Send + Sync {
+  /// Samples between `start` and `end` with step of alert
+  async fn query_range(
+    &self,
+    alert: &Alert,
+    query: &str,
+    start: i64,
+    end: i64,
+  ) -> anyhow::Result<HashMap<String, Values>>;
+
+  /// Single sample per label at `time`
+  async fn query_instant(&self, alert: &Alert, query: &str, time: i64) -> anyhow::Result<HashMap<String, Values>>;
+}
+
+pub fn create(config: &DatasourceConfig) -> Box<dyn Datasource> {
+  match config.kind {
+    DatasourceType::Prometheus => Box::new(prometheus::Prometheus::new(config.clone())),
+    DatasourceType::Graphite => Box::new(graphite::Graphite::new(config.clone())),
+  }
+}
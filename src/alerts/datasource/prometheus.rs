@@ -0,0 +1,112 @@
+use crate::alerts::config::{Alert, DatasourceConfig};
+use crate::alerts::datasource::Datasource;
+use crate::alerts::Values;
+use async_trait::async_trait;
+use json::JsonValue;
+use std::collections::HashMap;
+
+/// Prometheus HTTP API, `/api/v1/query_range` and `/api/v1/query`
+pub struct Prometheus {
+  config: DatasourceConfig,
+}
+
+impl Prometheus {
+  pub fn new(config: DatasourceConfig) -> Prometheus {
+    Prometheus { config }
+  }
+
+  async fn request(&self, path: &str, params: &[(String, String)]) -> anyhow::Result<JsonValue> {
+    let response = self.config.fetch(path, params).await?;
+
+    // Errors of query itself are not retried
+    if response["status"] == "error" {
+      return Err(anyhow::anyhow!("{}: {}", response["errorType"], response["error"]));
+    }
+
+    Ok(response)
+  }
+}
+
+#[async_trait]
+impl Datasource for Prometheus {
+  async fn query_range(
+    &self,
+    alert: &Alert,
+    query: &str,
+    start: i64,
+    end: i64,
+  ) -> anyhow::Result<HashMap<String, Values>> {
+    let mut result: HashMap<String, Values> = HashMap::new();
+
+    let params = alert.query_params(vec![
+      ("query", query.to_owned()),
+      ("start", start.to_string()),
+      ("end", end.to_string()),
+      ("step", alert.step.clone()),
+    ]);
+    let response = self.request("api/v1/query_range", &params).await?;
+    let response = &response["data"]["result"];
+
+    for response_result in response.members() {
+      let mut values = Values::new();
+
+      for value in response_result["values"].members() {
+        if let Some(sample) = parse_sample(value) {
+          values.push(sample);
+        }
+      }
+
+      result.insert(alert.format_label(&response_result["metric"]), values);
+    }
+
+    Ok(result)
+  }
+
+  async fn query_instant(&self, alert: &Alert, query: &str, time: i64) -> anyhow::Result<HashMap<String, Values>> {
+    let mut result: HashMap<String, Values> = HashMap::new();
+
+    let params = alert.query_params(vec![("query", query.to_owned()), ("time", time.to_string())]);
+    let response = self.request("api/v1/query", &params).await?;
+    let response = &response["data"]["result"];
+
+    for response_result in response.members() {
+      let values = parse_sample(&response_result["value"]).into_iter().collect();
+
+      result.insert(alert.format_label(&response_result["metric"]), values);
+    }
+
+    Ok(result)
+  }
+}
+
+/// Storage returns NaN and ±Inf as strings, they are dropped together with malformed samples
+fn parse_sample(value: &JsonValue) -> Option<(u64, f32)> {
+  match value[1].as_str().map(str::parse) {
+    Some(Ok(val)) if f32::is_finite(val) => Some((value[0].as_u64().unwrap_or(0), val)),
+    _ => None,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse_finite_sample() {
+    assert_eq!(Some((10, 1.5)), parse_sample(&json::array![10, "1.5"]));
+  }
+
+  #[test]
+  fn drop_nan_and_inf() {
+    assert_eq!(None, parse_sample(&json::array![10, "NaN"]));
+    assert_eq!(None, parse_sample(&json::array![10, "+Inf"]));
+    assert_eq!(None, parse_sample(&json::array![10, "-Inf"]));
+  }
+
+  #[test]
+  fn drop_malformed_sample() {
+    assert_eq!(None, parse_sample(&json::array![10, "abc"]));
+    assert_eq!(None, parse_sample(&json::array![10, 1.5]));
+    assert_eq!(None, parse_sample(&json::array![]));
+  }
+}
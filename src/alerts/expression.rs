@@ -0,0 +1,471 @@
+use crate::alerts::{reducers, Values};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+  Number(f32),
+  Reducer(Reducer),
+  Neg(Box<Expr>),
+  Not(Box<Expr>),
+  Binary(Box<Expr>, Op, Box<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reducer {
+  Avg,
+  Min,
+  Max,
+  First,
+  Last,
+  Sum,
+  Count,
+  Stddev,
+  Percentile(f32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+  Add,
+  Sub,
+  Mul,
+  Div,
+  Less,
+  LessEq,
+  Greater,
+  GreaterEq,
+  Eq,
+  NotEq,
+  And,
+  Or,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+  Number(f32),
+  Ident(String),
+  Op(&'static str),
+}
+
+/// Parses expression like `avg > 90 && max > 99`, result of expression should be boolean
+pub fn parse(input: &str) -> anyhow::Result<Expr> {
+  let tokens = tokenize(input)?;
+  let mut parser = Parser { tokens, position: 0 };
+
+  let expr = parser.or()?;
+  if let Some(token) = parser.tokens.get(parser.position) {
+    return Err(anyhow::anyhow!("Unexpected {:?} after expression", token));
+  }
+  if !expr.is_boolean()? {
+    return Err(anyhow::anyhow!("Expression should be a comparison, like `avg > 90`"));
+  }
+
+  Ok(expr)
+}
+
+impl Expr {
+  /// Booleans are represented as 1.0 and 0.0
+  pub fn evaluate(&self, values: &Values) -> f32 {
+    match self {
+      Expr::Number(number) => *number,
+      Expr::Reducer(reducer) => reducer.apply(values),
+      Expr::Neg(expr) => -expr.evaluate(values),
+      Expr::Not(expr) => from_bool(expr.evaluate(values) == 0.0),
+      Expr::Binary(left, op, right) => {
+        let (left, right) = (left.evaluate(values), right.evaluate(values));
+
+        match op {
+          Op::Add => left + right,
+          Op::Sub => left - right,
+          Op::Mul => left * right,
+          Op::Div => left / right,
+          Op::Less => from_bool(left < right),
+          Op::LessEq => from_bool(left <= right),
+          Op::Greater => from_bool(left > right),
+          Op::GreaterEq => from_bool(left >= right),
+          Op::Eq => from_bool(left == right),
+          Op::NotEq => from_bool(left != right),
+          Op::And => from_bool(left != 0.0 && right != 0.0),
+          Op::Or => from_bool(left != 0.0 || right != 0.0),
+        }
+      }
+    }
+  }
+
+  /// Reducers used in expression, without duplicates
+  pub fn reducers(&self) -> Vec<Reducer> {
+    let mut result = vec![];
+
+    match self {
+      Expr::Number(_) => {}
+      Expr::Reducer(reducer) => result.push(reducer.clone()),
+      Expr::Neg(expr) | Expr::Not(expr) => result = expr.reducers(),
+      Expr::Binary(left, _, right) => {
+        result = left.reducers();
+        for reducer in right.reducers() {
+          if !result.contains(&reducer) {
+            result.push(reducer);
+          }
+        }
+      }
+    }
+
+    result
+  }
+
+  fn is_boolean(&self) -> anyhow::Result<bool> {
+    match self {
+      Expr::Number(_) | Expr::Reducer(_) => Ok(false),
+      Expr::Neg(expr) => expect_kind(expr, false, "-").map(|_| false),
+      Expr::Not(expr) => expect_kind(expr, true, "!").map(|_| true),
+      Expr::Binary(left, op, right) => {
+        let (operands, result) = match op {
+          Op::Add | Op::Sub | Op::Mul | Op::Div => (false, false),
+          Op::Less | Op::LessEq | Op::Greater | Op::GreaterEq | Op::Eq | Op::NotEq => (false, true),
+          Op::And | Op::Or => (true, true),
+        };
+
+        expect_kind(left, operands, op.symbol())?;
+        expect_kind(right, operands, op.symbol())?;
+        Ok(result)
+      }
+    }
+  }
+}
+
+impl Reducer {
+  pub fn apply(&self, values: &Values) -> f32 {
+    match self {
+      Reducer::Avg => reducers::avg(values),
+      Reducer::Min => reducers::min(values),
+      Reducer::Max => reducers::max(values),
+      Reducer::First => values.first().map(|(_, v)| *v).unwrap_or(0.0),
+      Reducer::Last => reducers::last(values),
+      Reducer::Sum => reducers::sum(values),
+      Reducer::Count => reducers::count(values),
+      Reducer::Stddev => reducers::mean_std(values).1,
+      Reducer::Percentile(quantile) => reducers::percentile(values, *quantile),
+    }
+  }
+
+  pub fn name(&self) -> String {
+    match self {
+      Reducer::Avg => "avg".to_owned(),
+      Reducer::Min => "min".to_owned(),
+      Reducer::Max => "max".to_owned(),
+      Reducer::First => "first".to_owned(),
+      Reducer::Last => "last".to_owned(),
+      Reducer::Sum => "sum".to_owned(),
+      Reducer::Count => "count".to_owned(),
+      Reducer::Stddev => "stddev".to_owned(),
+      Reducer::Percentile(quantile) => format!("p{}", (quantile * 1000.0).round() / 10.0),
+    }
+  }
+
+  fn from_name(name: &str) -> Option<Reducer> {
+    match name {
+      "avg" => Some(Reducer::Avg),
+      "min" => Some(Reducer::Min),
+      "max" => Some(Reducer::Max),
+      "first" => Some(Reducer::First),
+      "last" => Some(Reducer::Last),
+      "sum" => Some(Reducer::Sum),
+      "count" => Some(Reducer::Count),
+      "stddev" => Some(Reducer::Stddev),
+      _ => {
+        // p5 is 5th percentile, p95 - 95th, p999 - 99.9th
+        let digits = name
+          .strip_prefix('p')
+          .filter(|d| !d.is_empty() && d.chars().all(|c| c.is_ascii_digit()))?;
+        let number: f32 = digits.parse().ok()?;
+
+        if number <= 100.0 {
+          Some(Reducer::Percentile(number / 100.0))
+        } else {
+          Some(Reducer::Percentile(format!("0.{}", digits).parse().ok()?))
+        }
+      }
+    }
+  }
+}
+
+impl Op {
+  fn symbol(&self) -> &'static str {
+    match self {
+      Op::Add => "+",
+      Op::Sub => "-",
+      Op::Mul => "*",
+      Op::Div => "/",
+      Op::Less => "<",
+      Op::LessEq => "<=",
+      Op::Greater => ">",
+      Op::GreaterEq => ">=",
+      Op::Eq => "==",
+      Op::NotEq => "!=",
+      Op::And => "&&",
+      Op::Or => "||",
+    }
+  }
+}
+
+fn from_bool(value: bool) -> f32 {
+  if value {
+    1.0
+  } else {
+    0.0
+  }
+}
+
+fn expect_kind(expr: &Expr, boolean: bool, op: &str) -> anyhow::Result<()> {
+  if expr.is_boolean()? == boolean {
+    return Ok(());
+  }
+
+  if boolean {
+    Err(anyhow::anyhow!("Operands of `{}` should be comparisons", op))
+  } else {
+    Err(anyhow::anyhow!("Operands of `{}` should be numbers or reducers", op))
+  }
+}
+
+const OPERATORS: [&str; 15] = [
+  "&&", "||", "<=", ">=", "==", "!=", "<", ">", "!", "+", "-", "*", "/", "(", ")",
+];
+
+fn tokenize(input: &str) -> anyhow::Result<Vec<Token>> {
+  let mut tokens = vec![];
+  let mut rest = input.trim_start();
+
+  while !rest.is_empty() {
+    let position = input.len() - rest.len();
+
+    if let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(*op)) {
+      tokens.push(Token::Op(op));
+      rest = &rest[op.len()..];
+    } else if rest.starts_with(|c: char| c.is_ascii_digit() || c == '.') {
+      let length = rest
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(rest.len());
+      let number = rest[..length]
+        .parse()
+        .map_err(|_| anyhow::anyhow!("Invalid number `{}` at {}", &rest[..length], position))?;
+
+      tokens.push(Token::Number(number));
+      rest = &rest[length..];
+    } else if rest.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+      let length = rest
+        .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+        .unwrap_or(rest.len());
+
+      tokens.push(Token::Ident(rest[..length].to_owned()));
+      rest = &rest[length..];
+    } else {
+      return Err(anyhow::anyhow!("Unexpected character at {}", position));
+    }
+
+    rest = rest.trim_start();
+  }
+
+  Ok(tokens)
+}
+
+struct Parser {
+  tokens: Vec<Token>,
+  position: usize,
+}
+
+impl Parser {
+  fn or(&mut self) -> anyhow::Result<Expr> {
+    self.binary(&[("||", Op::Or)], Parser::and)
+  }
+
+  fn and(&mut self) -> anyhow::Result<Expr> {
+    self.binary(&[("&&", Op::And)], Parser::comparison)
+  }
+
+  fn comparison(&mut self) -> anyhow::Result<Expr> {
+    let ops = [
+      ("<=", Op::LessEq),
+      (">=", Op::GreaterEq),
+      ("==", Op::Eq),
+      ("!=", Op::NotEq),
+      ("<", Op::Less),
+      (">", Op::Greater),
+    ];
+
+    self.binary(&ops, Parser::sum)
+  }
+
+  fn sum(&mut self) -> anyhow::Result<Expr> {
+    self.binary(&[("+", Op::Add), ("-", Op::Sub)], Parser::product)
+  }
+
+  fn product(&mut self) -> anyhow::Result<Expr> {
+    self.binary(&[("*", Op::Mul), ("/", Op::Div)], Parser::unary)
+  }
+
+  fn unary(&mut self) -> anyhow::Result<Expr> {
+    if self.accept("-") {
+      return Ok(Expr::Neg(Box::new(self.unary()?)));
+    }
+    if self.accept("!") {
+      return Ok(Expr::Not(Box::new(self.unary()?)));
+    }
+
+    self.primary()
+  }
+
+  fn primary(&mut self) -> anyhow::Result<Expr> {
+    let token = self.tokens.get(self.position).cloned();
+    self.position += 1;
+
+    match token {
+      Some(Token::Number(number)) => Ok(Expr::Number(number)),
+      Some(Token::Ident(name)) => match Reducer::from_name(&name) {
+        Some(reducer) => Ok(Expr::Reducer(reducer)),
+        None => Err(anyhow::anyhow!(
+          "Unknown reducer `{}`, expected avg, min, max, first, last, sum, count, stddev or pNN",
+          name
+        )),
+      },
+      Some(Token::Op("(")) => {
+        let expr = self.or()?;
+        if !self.accept(")") {
+          return Err(anyhow::anyhow!("Missing closing parenthesis"));
+        }
+
+        Ok(expr)
+      }
+      Some(token) => Err(anyhow::anyhow!("Unexpected {:?}", token)),
+      None => Err(anyhow::anyhow!("Unexpected end of expression")),
+    }
+  }
+
+  /// Left-associative chain of `operand (op operand)*`
+  fn binary(&mut self, ops: &[(&str, Op)], operand: fn(&mut Parser) -> anyhow::Result<Expr>) -> anyhow::Result<Expr> {
+    let mut expr = operand(self)?;
+
+    'chain: loop {
+      for (symbol, op) in ops {
+        if self.accept(symbol) {
+          expr = Expr::Binary(Box::new(expr), *op, Box::new(operand(self)?));
+          continue 'chain;
+        }
+      }
+
+      return Ok(expr);
+    }
+  }
+
+  fn accept(&mut self, symbol: &str) -> bool {
+    if matches!(self.tokens.get(self.position), Some(Token::Op(op)) if *op == symbol) {
+      self.position += 1;
+      return true;
+    }
+
+    false
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn binary(left: Expr, op: Op, right: Expr) -> Expr {
+    Expr::Binary(Box::new(left), op, Box::new(right))
+  }
+
+  fn reducer(reducer: Reducer) -> Expr {
+    Expr::Reducer(reducer)
+  }
+
+  fn error(input: &str) -> String {
+    parse(input).unwrap_err().to_string()
+  }
+
+  #[test]
+  fn and_binds_tighter_than_or() {
+    let expected = binary(
+      binary(
+        binary(reducer(Reducer::Avg), Op::Greater, Expr::Number(1.0)),
+        Op::And,
+        binary(reducer(Reducer::Max), Op::Greater, Expr::Number(2.0)),
+      ),
+      Op::Or,
+      binary(reducer(Reducer::Last), Op::Less, Expr::Number(0.0)),
+    );
+
+    assert_eq!(expected, parse("avg > 1 && max > 2 || last < 0").unwrap());
+  }
+
+  #[test]
+  fn arithmetic_binds_tighter_than_comparison() {
+    let expected = binary(
+      binary(
+        reducer(Reducer::Avg),
+        Op::Add,
+        binary(reducer(Reducer::Stddev), Op::Mul, Expr::Number(2.0)),
+      ),
+      Op::Less,
+      Expr::Neg(Box::new(reducer(Reducer::Min))),
+    );
+
+    assert_eq!(expected, parse("avg + stddev * 2 < -min").unwrap());
+  }
+
+  #[test]
+  fn percentile_names() {
+    assert_eq!(Some(Reducer::Percentile(0.95)), Reducer::from_name("p95"));
+    assert_eq!(Some(Reducer::Percentile(0.999)), Reducer::from_name("p999"));
+    assert_eq!(Some(Reducer::Percentile(0.05)), Reducer::from_name("p5"));
+    assert_eq!(Some(Reducer::Percentile(1.0)), Reducer::from_name("p100"));
+    assert_eq!(None, Reducer::from_name("p"));
+    assert_eq!(None, Reducer::from_name("p9x"));
+
+    assert_eq!("p95", Reducer::Percentile(0.95).name());
+    assert_eq!("p99.9", Reducer::Percentile(0.999).name());
+  }
+
+  #[test]
+  fn not_equal_is_not_negation() {
+    assert_eq!(
+      binary(reducer(Reducer::Avg), Op::NotEq, Expr::Number(1.0)),
+      parse("avg != 1").unwrap()
+    );
+    assert_eq!(
+      Expr::Not(Box::new(binary(reducer(Reducer::Avg), Op::Eq, Expr::Number(1.0)))),
+      parse("!(avg == 1)").unwrap()
+    );
+  }
+
+  #[test]
+  fn evaluate_against_values() {
+    let values = vec![(0, 1.0), (10, 2.0), (20, 6.0)];
+
+    assert_eq!(1.0, parse("avg == 3 && last > first").unwrap().evaluate(&values));
+    assert_eq!(0.0, parse("max < 5 || !(sum == 9)").unwrap().evaluate(&values));
+    assert_eq!(1.0, parse("count * 2 >= 6").unwrap().evaluate(&values));
+  }
+
+  #[test]
+  fn reducers_without_duplicates() {
+    let expr = parse("avg > 1 && (max - avg) > p95").unwrap();
+
+    assert_eq!(
+      vec![Reducer::Avg, Reducer::Max, Reducer::Percentile(0.95)],
+      expr.reducers()
+    );
+  }
+
+  #[test]
+  fn rejects_invalid_expressions() {
+    assert_eq!("Expression should be a comparison, like `avg > 90`", error("avg"));
+    assert_eq!("Operands of `&&` should be comparisons", error("avg > 1 && 2"));
+    assert_eq!("Operands of `>` should be numbers or reducers", error("(avg > 1) > 2"));
+    assert_eq!("Operands of `!` should be comparisons", error("!avg"));
+    assert_eq!("Unexpected end of expression", error("avg > 1 &&"));
+    assert_eq!("Missing closing parenthesis", error("(avg > 1"));
+    assert_eq!("Unexpected Op(\")\") after expression", error("avg > 1)"));
+    assert_eq!("Unexpected character at 4", error("avg # 1"));
+    assert_eq!("Invalid number `1.2.3` at 6", error("avg > 1.2.3"));
+    assert!(error("median > 1").starts_with("Unknown reducer `median`"));
+  }
+}
@@ -1,12 +1,18 @@
-use crate::alerts::config::{Alert, AlertCondition, AlertStatus, Condition};
-use crate::db::alert_state::{get_alert_state, update_alert_state};
+use crate::alerts::config::{Aggregate, Alert, AlertCondition, AlertStatus, Composite, Config, EvaluationMode};
+use crate::db::alert_state::{get_alert_state, update_alert_state, AlertState};
+use crate::util::formatted_duration;
+use futures_util::stream::{self, StreamExt};
 use std::collections::HashMap;
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::task::JoinHandle;
 
 mod chart;
 pub mod config;
+mod datasource;
+mod expression;
 mod notifier;
+mod reducers;
 
 type Values = Vec<(u64, f32)>;
 
@@ -25,147 +31,664 @@ pub fn launch_loop() -> JoinHandle<()> {
 }
 
 pub async fn process_alerts() -> anyhow::Result<()> {
-  let config = crate::CONFIG.alerts.clone();
+  let config = Arc::new(crate::CONFIG.alerts.clone());
+
+  // Few alerts are evaluated at a time, composites go after the alerts they combine
+  let (composites, alerts): (Vec<Alert>, Vec<Alert>) = config
+    .alerts
+    .iter()
+    .cloned()
+    .partition(|alert| alert.composite.is_some());
 
   let mut trigger_notifier = false;
-  for alert in config.alerts {
-    let mut state = get_alert_state(&alert).await?;
+  for alerts in [alerts, composites] {
+    let results: Vec<_> = stream::iter(alerts)
+      .map(|alert| {
+        let config = config.clone();
+        async move { process_alert(&config, &alert).await }
+      })
+      .buffer_unordered(config.max_concurrent_alerts.max(1))
+      .collect()
+      .await;
 
-    let new_statuses = if state.counter >= alert.interval_s {
-      state.counter = 1;
-      trigger_notifier = true;
+    for result in results {
+      trigger_notifier |= result?;
+    }
+  }
 
-      calculate_status(&alert, &state.status).await?
-    } else {
-      state.counter += 1;
-      state.status.clone()
-    };
+  if trigger_notifier {
+    notifier::refresh_pinned().await?;
+  }
 
-    let repeat_needed: bool =
-      state.status_last_repeated + config.repeat_interval_secs < chrono::Utc::now().timestamp() as u64;
-
-    if state.status != new_statuses || repeat_needed {
-      for (label, new_status) in new_statuses.clone() {
-        if (!repeat_needed || new_statuses.get(&label).unwrap() == &AlertStatus::Ok)
-          && (state.status.contains_key(&label)
-            && state.status.get(&label).unwrap() == new_statuses.get(&label).unwrap())
-        {
-          // Skip if status is not changed
-          continue;
-        }
+  Ok(())
+}
+
+/// Evaluates alert if its interval passed and sends notifications, returns true if alert was evaluated
+async fn process_alert(config: &Config, alert: &Alert) -> anyhow::Result<bool> {
+  let critical_repeat_interval = config.critical_repeat_interval();
+  let mut evaluated = false;
+  let mut state = get_alert_state(alert).await?;
 
-        let alert_name = alert.name.clone();
+  let new_statuses = if state.counter >= alert.interval_s {
+    state.counter = 1;
+    evaluated = true;
+
+    calculate_status(alert, &mut state).await?
+  } else {
+    state.counter += 1;
+    state.status.clone()
+  };
+
+  let now = chrono::Utc::now().timestamp() as u64;
+  let repeat_needed: bool = state.status_last_repeated + config.repeat_interval_secs < now;
+  let critical_repeat_needed: bool = state.critical_last_repeated + critical_repeat_interval < now;
+
+  if state.status != new_statuses || repeat_needed || critical_repeat_needed {
+    for (label, new_status) in new_statuses.clone() {
+      let repeat_needed = repeat_needed || (critical_repeat_needed && new_status == AlertStatus::Err);
+
+      // Resolved and missing labels are not repeated
+      if (!repeat_needed || matches!(new_status, AlertStatus::Ok | AlertStatus::NoData))
+        && (state.status.contains_key(&label) && state.status.get(&label).unwrap() == new_statuses.get(&label).unwrap())
+      {
+        // Skip if status is not changed
+        continue;
+      }
+
+      // Pending labels are never notified, as well as labels resolved while pending
+      let silent = new_status == AlertStatus::Pending
+        || (new_status == AlertStatus::Ok && state.status.get(&label) == Some(&AlertStatus::Pending));
+
+      let alert_name = alert.name.clone();
+      if !silent {
         match notifier::send_alert(alert.clone(), label.clone(), &state, new_status.clone()).await {
           Ok(_) => {}
           Err(e) => {
             log::error!("Failed to send notification for {}: {:?}", alert_name, e)
           }
         }
-
-        state.update_status(
-          label.clone(),
-          new_status,
-          !state.status.contains_key(&label) || state.status.get(&label).unwrap() != new_statuses.get(&label).unwrap(),
-        );
       }
 
-      if repeat_needed {
-        if new_statuses.is_empty() {
-          match notifier::send_no_data_alert(alert.clone()).await {
-            Ok(_) => {}
-            Err(e) => {
-              log::error!("Failed to send notification for {}: {:?}", alert.name, e)
-            }
+      state.update_status(
+        label.clone(),
+        new_status,
+        !state.status.contains_key(&label) || state.status.get(&label).unwrap() != new_statuses.get(&label).unwrap(),
+      );
+    }
+
+    if repeat_needed {
+      // Alert has no known labels
+      if new_statuses.is_empty() {
+        match notifier::send_no_data_alert(alert.clone()).await {
+          Ok(_) => {}
+          Err(e) => {
+            log::error!("Failed to send notification for {}: {:?}", alert.name, e)
           }
         }
-
-        state.update_repeat();
       }
+
+      state.update_repeat();
     }
 
-    update_alert_state(state).await?;
+    if repeat_needed || critical_repeat_needed {
+      state.update_critical_repeat();
+    }
   }
 
-  if trigger_notifier {
-    notifier::refresh_pinned().await?;
-  }
+  update_alert_state(state).await?;
 
-  Ok(())
+  Ok(evaluated)
 }
 
-async fn calculate_status(
-  alert: &Alert,
-  old_statuses: &HashMap<String, AlertStatus>,
-) -> anyhow::Result<HashMap<String, AlertStatus>> {
+async fn calculate_status(alert: &Alert, state: &mut AlertState) -> anyhow::Result<HashMap<String, AlertStatus>> {
+  if let Some(composite) = &alert.composite {
+    return calculate_composite_status(alert, composite, state).await;
+  }
+
   let end = chrono::Utc::now().timestamp();
-  let start = end - (alert.condition_range_s as i64);
-  let values = match request_values(alert, start, end).await {
+  let start = end - (alert.evaluation_range_s() as i64);
+  let condition_start = (end - alert.condition_range_s as i64) as u64;
+  let values = match request_evaluation_values(alert, start, end).await {
     Ok(val) => val,
     Err(err) => {
       log::error!("Failed to request metrics for {}: {:?}", alert.name, err);
-      HashMap::new()
+      return Ok(datasource_error(alert, state, &err));
     }
   };
 
+  // Error of the whole alert is resolved once datasource responds and forgotten after that,
+  // aggregate label is evaluated below
+  let mut firing: HashMap<String, AlertStatus> = HashMap::new();
+  let label = alert_label(alert);
+  if alert.aggregate.is_none() && !values.contains_key(&label) {
+    match state.status.get(&label) {
+      Some(AlertStatus::DatasourceError(_)) => {
+        log::info!("Datasource of {} responds again", alert.name);
+        clear_label(state, &label);
+        firing.insert(label, AlertStatus::Ok);
+      }
+      Some(AlertStatus::Ok) => state.remove_label(&label),
+      _ => {}
+    }
+  }
+
+  // Known labels are marked as missing, alert without them gets no data notification
   if values.is_empty() {
-    return Ok(HashMap::new());
+    mark_missing(state, &mut firing, chrono::Utc::now().timestamp());
+
+    return Ok(firing);
   }
 
-  let mut firing: HashMap<String, AlertStatus> = HashMap::new();
+  let previous = match alert.compare_offset_s() {
+    Some(offset) => match request_evaluation_values(alert, start - offset as i64, end - offset as i64).await {
+      Ok(val) => val,
+      Err(err) => {
+        log::error!("Failed to request previous period for {}: {:?}", alert.name, err);
+        return Ok(datasource_error(alert, state, &err));
+      }
+    },
+    None => HashMap::new(),
+  };
+
+  let now = chrono::Utc::now().timestamp();
+  // Labels with verdict, aggregate percentage is calculated of them
+  let mut total = 0;
+  let mut breaching: Vec<(String, AlertStatus)> = vec![];
   for (label, values) in values {
-    let result = match alert.condition.clone() {
-      AlertCondition::Avg {
-        condition,
-        value,
-        value_ok,
+    // Verdict on too few samples is not reliable
+    let reduced = if values.is_empty() || values.len() < alert.min_samples {
+      f32::NAN
+    } else {
+      match alert.condition {
+        AlertCondition::Avg { .. } => reducers::avg(&values),
+        AlertCondition::Max { .. } => reducers::max(&values),
+        AlertCondition::Min { .. } => reducers::min(&values),
+        AlertCondition::Last { .. } => reducers::last(&values),
+        AlertCondition::Sum { .. } => reducers::sum(&values),
+        AlertCondition::Count { .. } => reducers::count(&values),
+        AlertCondition::Percentile { quantile, .. } => reducers::percentile(&values, quantile),
+        AlertCondition::Change {
+          ref method,
+          ref condition,
+          ..
+        } => reducers::change(&values, method, condition).0,
+        AlertCondition::DutyCycle {
+          ref condition,
+          threshold,
+          ..
+        } => reducers::duty_cycle(&values, condition, threshold),
+        AlertCondition::Expression { ref parsed, .. } => {
+          parsed.as_ref().map_or(f32::NAN, |expr| expr.evaluate(&values))
+        }
+        AlertCondition::Compare { ref method, .. } => reducers::compare(&values, previous.get(&label), method),
+        AlertCondition::Forecast { horizon_s, .. } => reducers::forecast(&values, end as u64 + horizon_s),
+        AlertCondition::Anomaly { min_std, .. } => reducers::anomaly(&values, condition_start, min_std).0,
+      }
+    };
+
+    // No verdict, like too few samples, anomaly of flat baseline or comparison without previous period
+    if reduced.is_nan() {
+      if alert.aggregate.is_none() {
+        clear_label(state, &label);
+        firing.insert(label, AlertStatus::NoData);
+      }
+
+      continue;
+    }
+    total += 1;
+
+    if let Some(aggregate) = &alert.aggregate {
+      // Labels are checked with hysteresis of the alert-level status
+      let old_status = state.status.get(&aggregate.label).cloned().unwrap_or_default();
+      if let Some(status) = alert.breached_status(reduced, &old_status) {
+        breaching.push((label, status));
+      }
+
+      continue;
+    }
+    // Result of expression is not useful in overview, its terms are shown in notification
+    if alert.condition.expression().is_none() {
+      state.values.insert(label.clone(), reduced);
+    }
+
+    let details = match alert.condition {
+      AlertCondition::Forecast { .. } => {
+        let (condition, value, _) = alert.condition.threshold();
+        reducers::time_to_cross(&values, end as u64, &condition.bounds(value)).map(|seconds| {
+          format!(
+            "Estimated to cross threshold in {}",
+            formatted_duration(chrono::Duration::seconds(seconds as i64)).trim()
+          )
+        })
+      }
+      AlertCondition::Expression {
+        ref expression,
+        parsed: Some(ref expr),
       } => {
-        let average = values.iter().map(|(_, v)| *v).reduce(|a, b| a + b).unwrap_or(0.0) / values.len() as f32;
-
-        let condition_value = if old_statuses
-          .get(&label)
-          .unwrap_or(&AlertStatus::Ok)
-          .eq(&AlertStatus::Ok)
-        {
-          value
-        } else {
-          value_ok
-        };
+        let terms = expr
+          .reducers()
+          .iter()
+          .map(|reducer| format!("{} = {:.2}", reducer.name(), reducer.apply(&values)))
+          .collect::<Vec<_>>();
 
-        match condition {
-          Condition::Less => average < condition_value,
-          Condition::Greater => average > condition_value,
-        }
+        Some(format!("{} ({})", expression, terms.join(", ")))
       }
+      _ => None,
+    };
+    match details {
+      Some(details) => state.details.insert(label.clone(), details),
+      None => state.details.remove(&label),
+    };
+
+    let old_status = state.status.get(&label).cloned().unwrap_or_default();
+    let breached = alert.breached_status(reduced, &old_status);
+
+    firing.insert(label.clone(), next_status(alert, state, &label, breached, now));
+  }
+
+  if let Some(aggregate) = &alert.aggregate {
+    return Ok(aggregate_status(alert, aggregate, state, breaching, total, now));
+  }
+
+  mark_missing(state, &mut firing, now);
+
+  Ok(firing)
+}
+
+/// Known labels are marked with error, alert without labels gets error of the whole alert
+fn datasource_error(alert: &Alert, state: &AlertState, err: &anyhow::Error) -> HashMap<String, AlertStatus> {
+  // Keep caption of notification short, errors of request include whole url
+  let err: String = err.to_string().chars().take(200).collect();
+
+  if state.status.is_empty() {
+    let mut firing = HashMap::new();
+    firing.insert(alert_label(alert), AlertStatus::DatasourceError(err));
+
+    return firing;
+  }
+
+  state
+    .status
+    .iter()
+    .map(|(label, status)| match status {
+      AlertStatus::DatasourceError(_) => (label.clone(), status.clone()),
+      _ => (label.clone(), AlertStatus::DatasourceError(err.clone())),
+    })
+    .collect()
+}
+
+/// Label of status that belongs to the whole alert
+fn alert_label(alert: &Alert) -> String {
+  match &alert.aggregate {
+    Some(aggregate) => aggregate.label.clone(),
+    None => alert.name.clone(),
+  }
+}
+
+fn aggregate_status(
+  alert: &Alert,
+  aggregate: &Aggregate,
+  state: &mut AlertState,
+  mut breaching: Vec<(String, AlertStatus)>,
+  total: usize,
+  now: i64,
+) -> HashMap<String, AlertStatus> {
+  // Status of the most severe breaching label
+  let breached = if aggregate.is_breached(breaching.len(), total) {
+    breaching
+      .iter()
+      .map(|(_, status)| status.clone())
+      .max_by_key(|status| status.severity())
+  } else {
+    None
+  };
+
+  breaching.sort_by(|a, b| a.0.cmp(&b.0));
+  let labels: Vec<String> = breaching.into_iter().map(|(label, _)| label).collect();
+
+  // Keep caption of notification short
+  let mut listed = labels.iter().take(20).cloned().collect::<Vec<_>>().join(", ");
+  if labels.len() > 20 {
+    listed += &format!(" and {} more", labels.len() - 20);
+  }
+  state.details.insert(
+    aggregate.label.clone(),
+    format!("{} of {} labels are breaching: {}", labels.len(), total, listed),
+  );
+  state.aggregated_labels = labels;
+
+  let mut firing: HashMap<String, AlertStatus> = HashMap::new();
+  firing.insert(
+    aggregate.label.clone(),
+    next_status(alert, state, &aggregate.label, breached, now),
+  );
+
+  firing
+}
+
+async fn calculate_composite_status(
+  alert: &Alert,
+  composite: &Composite,
+  state: &mut AlertState,
+) -> anyhow::Result<HashMap<String, AlertStatus>> {
+  let mut statuses: HashMap<String, HashMap<String, AlertStatus>> = HashMap::new();
+  for name in composite.alert_names() {
+    // Referenced alerts are validated in init_config, safe to unwrap
+    let other = crate::CONFIG.alerts.alerts.iter().find(|a| a.name == name).unwrap();
+
+    statuses.insert(name, get_alert_state(other).await?.status);
+  }
+
+  let mut labels: Vec<String> = statuses.values().flat_map(|s| s.keys().cloned()).collect();
+  labels.sort();
+  labels.dedup();
+
+  if labels.is_empty() {
+    return Ok(HashMap::new());
+  }
+
+  let now = chrono::Utc::now().timestamp();
+  let mut firing: HashMap<String, AlertStatus> = HashMap::new();
+  for label in labels {
+    let mut details = statuses
+      .iter()
+      .map(|(name, statuses)| format!("{}: {}", name, statuses.get(&label).unwrap_or(&AlertStatus::NoData)))
+      .collect::<Vec<_>>();
+    details.sort();
+    state.details.insert(label.clone(), details.join(", "));
+
+    let breached = if composite.evaluate(&label, &statuses) {
+      Some(AlertStatus::Err)
+    } else {
+      None
     };
 
-    firing.insert(label.clone(), if result { AlertStatus::Err } else { AlertStatus::Ok });
+    firing.insert(label.clone(), next_status(alert, state, &label, breached, now));
   }
 
+  mark_missing(state, &mut firing, now);
+
   Ok(firing)
 }
 
+/// Applies `for_s` and `keep_firing_for_s` of alert to the breached status of label
+fn next_status(
+  alert: &Alert,
+  state: &mut AlertState,
+  label: &str,
+  breached: Option<AlertStatus>,
+  now: i64,
+) -> AlertStatus {
+  let old_status = state.status.get(label).cloned().unwrap_or_default();
+
+  match breached {
+    None => {
+      state.pending_since.remove(label);
+
+      if alert.keep_firing_for_s == 0 || !old_status.is_firing() {
+        AlertStatus::Ok
+      } else {
+        if !state.resolving_since.contains_key(label) {
+          state.resolving_since.insert(label.to_owned(), now);
+          *state.suppressed_resolves.entry(label.to_owned()).or_insert(0) += 1;
+        }
+
+        if (now - state.resolving_since[label]) as u64 >= alert.keep_firing_for_s {
+          state.resolving_since.remove(label);
+          AlertStatus::Ok
+        } else {
+          old_status
+        }
+      }
+    }
+    Some(status) if alert.for_s == 0 || old_status.is_firing() => {
+      state.resolving_since.remove(label);
+      status
+    }
+    Some(status) => {
+      let since = *state.pending_since.entry(label.to_owned()).or_insert(now);
+
+      if (now - since) as u64 >= alert.for_s {
+        status
+      } else {
+        AlertStatus::Pending
+      }
+    }
+  }
+}
+
+/// Labels reported before, but missing from storage now. Labels missing longer than retention are forgotten
+fn mark_missing(state: &mut AlertState, firing: &mut HashMap<String, AlertStatus>, now: i64) {
+  let retention = crate::CONFIG.alerts.no_data_retention_secs;
+  let missing = state
+    .status
+    .keys()
+    .filter(|label| !firing.contains_key(*label))
+    .cloned()
+    .collect::<Vec<_>>();
+
+  for label in missing {
+    let expired = retention > 0
+      && state.status.get(&label) == Some(&AlertStatus::NoData)
+      && state.status_last_changed.get(&label).cloned().unwrap_or(0) + (retention as i64) < now;
+    if expired {
+      log::info!("Label {} is missing for {}s, forgetting it", label, retention);
+      state.remove_label(&label);
+      continue;
+    }
+
+    clear_label(state, &label);
+    firing.insert(label, AlertStatus::NoData);
+  }
+}
+
+/// Forgets calculated value and timers of label without verdict
+fn clear_label(state: &mut AlertState, label: &str) {
+  state.values.remove(label);
+  state.details.remove(label);
+  state.pending_since.remove(label);
+  state.resolving_since.remove(label);
+}
+
+/// Samples condition is checked on, instant alerts get single sample per label at `end`
+async fn request_evaluation_values(alert: &Alert, start: i64, end: i64) -> anyhow::Result<HashMap<String, Values>> {
+  if alert.mode == EvaluationMode::Range {
+    return request_values(alert, start, end).await;
+  }
+
+  if alert.is_ratio() {
+    let numerator = request_instant(alert, &alert.numerator_query, end).await?;
+    let denominator = request_instant(alert, &alert.denominator_query, end).await?;
+
+    return Ok(ratio_values(numerator, denominator));
+  }
+
+  request_instant(alert, &alert.query, end).await
+}
+
 pub async fn request_values(alert: &Alert, start: i64, end: i64) -> anyhow::Result<HashMap<String, Values>> {
+  if alert.is_ratio() {
+    let numerator = request_query(alert, &alert.numerator_query, start, end).await?;
+    let denominator = request_query(alert, &alert.denominator_query, start, end).await?;
+
+    return Ok(ratio_values(numerator, denominator));
+  }
+
+  request_query(alert, &alert.query, start, end).await
+}
+
+/// Joins series by label and timestamp, samples with zero denominator are skipped
+fn ratio_values(numerator: HashMap<String, Values>, denominator: HashMap<String, Values>) -> HashMap<String, Values> {
   let mut result: HashMap<String, Values> = HashMap::new();
-  let datasource = alert.datasource_instance();
-
-  let response = datasource
-    .fetch(format!(
-      "api/v1/query_range?query={}&start={}&end={}&step={}",
-      alert.query, start, end, alert.step
-    ))
-    .await?;
-  let response = &response["data"]["result"];
-
-  for response_result in response.members() {
-    let mut values = Values::new();
-
-    for value in response_result["values"].members() {
-      let val: f32 = value[1].as_str().unwrap_or("0").parse().unwrap_or(0.0);
-      values.push((value[0].as_u64().unwrap_or(0), val));
-    }
 
-    result.insert(alert.format_label(&response_result["metric"]), values);
+  for (label, numerator) in numerator {
+    let denominator: HashMap<u64, f32> = match denominator.get(&label) {
+      Some(values) => values.iter().cloned().collect(),
+      None => continue,
+    };
+
+    let values = numerator
+      .into_iter()
+      .filter_map(|(timestamp, value)| match denominator.get(&timestamp) {
+        Some(divisor) if *divisor != 0.0 => Some((timestamp, value / divisor)),
+        _ => None,
+      })
+      .collect();
+    result.insert(label, values);
   }
 
-  Ok(result)
+  result
+}
+
+pub async fn request_query(
+  alert: &Alert,
+  query: &str,
+  start: i64,
+  end: i64,
+) -> anyhow::Result<HashMap<String, Values>> {
+  alert.datasource_instance().query_range(alert, query, start, end).await
+}
+
+async fn request_instant(alert: &Alert, query: &str, time: i64) -> anyhow::Result<HashMap<String, Values>> {
+  alert.datasource_instance().query_instant(alert, query, time).await
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Evaluates label at `now` and stores its status, same as process_alerts does
+  fn evaluate(alert: &Alert, state: &mut AlertState, breached: Option<AlertStatus>, now: i64) -> AlertStatus {
+    let status = next_status(alert, state, "label", breached, now);
+    state.status.insert("label".to_owned(), status.clone());
+
+    status
+  }
+
+  #[test]
+  fn breached_label_is_pending_for_duration() {
+    let alert = Alert {
+      for_s: 60,
+      ..Default::default()
+    };
+    let mut state = AlertState::default();
+
+    assert_eq!(
+      AlertStatus::Pending,
+      evaluate(&alert, &mut state, Some(AlertStatus::Err), 0)
+    );
+    assert_eq!(
+      AlertStatus::Pending,
+      evaluate(&alert, &mut state, Some(AlertStatus::Err), 59)
+    );
+    assert_eq!(
+      AlertStatus::Err,
+      evaluate(&alert, &mut state, Some(AlertStatus::Err), 60)
+    );
+    // Firing label changes severity without waiting
+    assert_eq!(
+      AlertStatus::Warning,
+      evaluate(&alert, &mut state, Some(AlertStatus::Warning), 61)
+    );
+  }
+
+  #[test]
+  fn pending_restarts_after_breach_is_gone() {
+    let alert = Alert {
+      for_s: 60,
+      ..Default::default()
+    };
+    let mut state = AlertState::default();
+
+    assert_eq!(
+      AlertStatus::Pending,
+      evaluate(&alert, &mut state, Some(AlertStatus::Err), 0)
+    );
+    assert_eq!(AlertStatus::Ok, evaluate(&alert, &mut state, None, 30));
+    assert_eq!(
+      AlertStatus::Pending,
+      evaluate(&alert, &mut state, Some(AlertStatus::Err), 40)
+    );
+    assert_eq!(
+      AlertStatus::Pending,
+      evaluate(&alert, &mut state, Some(AlertStatus::Err), 90)
+    );
+    assert_eq!(
+      AlertStatus::Err,
+      evaluate(&alert, &mut state, Some(AlertStatus::Err), 100)
+    );
+  }
+
+  #[test]
+  fn firing_label_resolves_after_delay() {
+    let alert = Alert {
+      keep_firing_for_s: 60,
+      ..Default::default()
+    };
+    let mut state = AlertState::default();
+
+    assert_eq!(
+      AlertStatus::Err,
+      evaluate(&alert, &mut state, Some(AlertStatus::Err), 0)
+    );
+    assert_eq!(AlertStatus::Err, evaluate(&alert, &mut state, None, 10));
+    assert_eq!(AlertStatus::Err, evaluate(&alert, &mut state, None, 69));
+    assert_eq!(AlertStatus::Ok, evaluate(&alert, &mut state, None, 70));
+    assert_eq!(Some(&1), state.suppressed_resolves.get("label"));
+  }
+
+  #[test]
+  fn resolve_delay_restarts_on_breach() {
+    let alert = Alert {
+      keep_firing_for_s: 60,
+      ..Default::default()
+    };
+    let mut state = AlertState::default();
+
+    assert_eq!(
+      AlertStatus::Err,
+      evaluate(&alert, &mut state, Some(AlertStatus::Err), 0)
+    );
+    assert_eq!(AlertStatus::Err, evaluate(&alert, &mut state, None, 10));
+    assert_eq!(
+      AlertStatus::Err,
+      evaluate(&alert, &mut state, Some(AlertStatus::Err), 50)
+    );
+    assert_eq!(AlertStatus::Err, evaluate(&alert, &mut state, None, 80));
+    assert_eq!(AlertStatus::Err, evaluate(&alert, &mut state, None, 139));
+    assert_eq!(AlertStatus::Ok, evaluate(&alert, &mut state, None, 140));
+  }
+
+  #[test]
+  fn label_that_was_not_firing_resolves_at_once() {
+    let alert = Alert {
+      for_s: 60,
+      keep_firing_for_s: 60,
+      ..Default::default()
+    };
+    let mut state = AlertState::default();
+
+    assert_eq!(
+      AlertStatus::Pending,
+      evaluate(&alert, &mut state, Some(AlertStatus::Err), 0)
+    );
+    assert_eq!(AlertStatus::Ok, evaluate(&alert, &mut state, None, 10));
+  }
+
+  #[test]
+  fn ratio_joins_samples_by_label_and_timestamp() {
+    let mut numerator = HashMap::new();
+    numerator.insert("a".to_owned(), vec![(0, 1.0), (10, 3.0), (20, 5.0), (30, 2.0)]);
+    numerator.insert("b".to_owned(), vec![(0, 1.0)]);
+
+    let mut denominator = HashMap::new();
+    // Zero and missing denominators are skipped
+    denominator.insert("a".to_owned(), vec![(0, 2.0), (10, 0.0), (20, 10.0)]);
+    denominator.insert("c".to_owned(), vec![(0, 1.0)]);
+
+    let ratio = ratio_values(numerator, denominator);
+
+    assert_eq!(1, ratio.len());
+    assert_eq!(Some(&vec![(0, 0.5), (20, 0.5)]), ratio.get("a"));
+  }
 }
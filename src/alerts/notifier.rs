@@ -23,33 +23,69 @@ pub async fn send_alert(
 
   let end = chrono::Utc::now().timestamp();
   let graph_start = end - (alert.graph_range_s as i64);
-  let png_data: Vec<u8> = chart::generate_chart(&alert, graph_start, end, Some(label.clone())).await?;
-  let image = Cow::from(png_data);
+  // Chart could be empty for labels missing from storage, message is sent without it then.
+  // Failed datasource is not requested again for chart
+  let image = if alert.composite.is_some() || matches!(new_status, AlertStatus::DatasourceError(_)) {
+    None
+  } else {
+    // Aggregate alerts draw all breaching labels, or every label when resolved
+    let draw_labels = match &alert.aggregate {
+      Some(_) if state.aggregated_labels.is_empty() => None,
+      Some(_) => Some(state.aggregated_labels.clone()),
+      None => Some(vec![label.clone()]),
+    };
+
+    match chart::generate_chart(&alert, graph_start, end, draw_labels).await {
+      Ok(png_data) => Some(Cow::from(png_data)),
+      Err(err) => {
+        log::warn!("Could not generate chart for {}({}): {}", alert.name, label, err);
+        None
+      }
+    }
+  };
 
   let duration = formatted_elapsed(state.status_last_changed(label.clone()));
+  let details = match state.details.get(&label) {
+    Some(details) => format!("\n{}", details),
+    None => "".to_owned(),
+  };
   let message = format!(
-    "{} {}({}): {} ({})\n{}\n\nWas: {} for {}",
+    "{} {}({}): {} ({})\n{}{}\n\nWas: {} for {}",
     new_status.emoji(),
     alert.name,
     label,
     new_status,
     alert.status_description(&new_status),
     alert.description,
+    details,
     state.status.get(&label).unwrap_or(&AlertStatus::NoData),
     duration
   );
 
+  // Warnings are delivered silently
+  let silent = new_status == AlertStatus::Warning;
   for user in users {
-    bot
-      .send_photo(
-        user.id.clone(),
-        InputFile::Memory {
-          data: image.clone(),
-          file_name: "alert.png".to_owned(),
-        },
-      )
-      .caption(message.clone())
-      .await?;
+    match image.clone() {
+      Some(image) => {
+        bot
+          .send_photo(
+            user.id.clone(),
+            InputFile::Memory {
+              data: image,
+              file_name: "alert.png".to_owned(),
+            },
+          )
+          .caption(message.clone())
+          .disable_notification(silent)
+          .await?;
+      }
+      None => {
+        bot
+          .send_message(user.id.clone(), message.clone())
+          .disable_notification(silent)
+          .await?;
+      }
+    }
   }
 
   Ok(())
@@ -0,0 +1,297 @@
+use crate::alerts::config::{ChangeMethod, CompareMethod, Condition};
+use crate::alerts::Values;
+
+pub fn avg(values: &Values) -> f32 {
+  sum(values) / values.len() as f32
+}
+
+pub fn max(values: &Values) -> f32 {
+  values.iter().map(|(_, v)| *v).fold(f32::MIN, f32::max)
+}
+
+pub fn min(values: &Values) -> f32 {
+  values.iter().map(|(_, v)| *v).fold(f32::MAX, f32::min)
+}
+
+pub fn last(values: &Values) -> f32 {
+  // Storage returns samples sorted by timestamp
+  values.last().map(|(_, v)| *v).unwrap_or(0.0)
+}
+
+pub fn sum(values: &Values) -> f32 {
+  values.iter().map(|(_, v)| *v).reduce(|a, b| a + b).unwrap_or(0.0)
+}
+
+pub fn count(values: &Values) -> f32 {
+  values.len() as f32
+}
+
+/// Linear interpolation between closest ranks, same as `quantile_over_time` in PromQL
+pub fn percentile(values: &Values, quantile: f32) -> f32 {
+  let mut sorted = values.iter().map(|(_, v)| *v).collect::<Vec<f32>>();
+  if sorted.is_empty() {
+    return f32::NAN;
+  }
+  sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+
+  let rank = quantile.clamp(0.0, 1.0) * (sorted.len() - 1) as f32;
+  let lower = rank.floor() as usize;
+  let upper = rank.ceil() as usize;
+
+  sorted[lower] + (sorted[upper] - sorted[lower]) * (rank - lower as f32)
+}
+
+/// Returns change and timestamps of samples it was measured between
+pub fn change(values: &Values, method: &ChangeMethod, condition: &Condition) -> (f32, u64, u64) {
+  let (first, last) = match (values.first(), values.last()) {
+    (Some(first), Some(last)) => (*first, *last),
+    _ => return (f32::NAN, 0, 0),
+  };
+
+  match method {
+    ChangeMethod::Delta => (last.1 - first.1, first.0, last.0),
+    ChangeMethod::Slope => {
+      let seconds = last.0.saturating_sub(first.0);
+      if seconds == 0 {
+        return (f32::NAN, first.0, last.0);
+      }
+
+      ((last.1 - first.1) / seconds as f32, first.0, last.0)
+    }
+    ChangeMethod::MaxStep => values
+      .windows(2)
+      .map(|step| (step[1].1 - step[0].1, step[0].0, step[1].0))
+      .fold((f32::NAN, 0, 0), |found, step| {
+        let larger = match condition {
+          Condition::Less => step.0 < found.0,
+          Condition::Greater => step.0 > found.0,
+          Condition::Outside { .. } | Condition::Inside { .. } => step.0.abs() > found.0.abs(),
+        };
+
+        // Greater looks for the largest rise, Less - for the largest drop, bands - for both
+        if found.0.is_nan() || larger {
+          step
+        } else {
+          found
+        }
+      }),
+  }
+}
+
+/// Returns (mean, standard deviation) of samples
+pub fn mean_std(values: &Values) -> (f32, f32) {
+  let mean = avg(values);
+  let variance = values.iter().map(|(_, v)| (v - mean).powi(2)).sum::<f32>() / values.len() as f32;
+
+  (mean, variance.sqrt())
+}
+
+/// Returns z-score of samples after `condition_start` against samples before it, with baseline mean and deviation.
+/// Z-score is NaN if deviation is not above `min_std`, any change of flat baseline would be infinitely anomalous
+pub fn anomaly(values: &Values, condition_start: u64, min_std: f32) -> (f32, f32, f32) {
+  let (baseline, recent): (Values, Values) = values.iter().partition(|(timestamp, _)| *timestamp < condition_start);
+  let (mean, std) = mean_std(&baseline);
+  if std.is_nan() || std <= min_std {
+    return (f32::NAN, mean, std);
+  }
+
+  ((avg(&recent) - mean) / std, mean, std)
+}
+
+/// Least squares fit of samples, returns (slope per second, value at timestamp 0)
+pub fn linear_regression(values: &Values) -> (f64, f64) {
+  let count = values.len() as f64;
+  let mean_t = values.iter().map(|(t, _)| *t as f64).sum::<f64>() / count;
+  let mean_v = values.iter().map(|(_, v)| *v as f64).sum::<f64>() / count;
+
+  let (mut covariance, mut variance) = (0.0, 0.0);
+  for (t, v) in values {
+    covariance += (*t as f64 - mean_t) * (*v as f64 - mean_v);
+    variance += (*t as f64 - mean_t).powi(2);
+  }
+
+  let slope = covariance / variance;
+  (slope, mean_v - slope * mean_t)
+}
+
+pub fn forecast(values: &Values, timestamp: u64) -> f32 {
+  let (slope, intercept) = linear_regression(values);
+
+  (intercept + slope * timestamp as f64) as f32
+}
+
+/// Seconds from `timestamp` until fitted line reaches the closest of `bounds`
+pub fn time_to_cross(values: &Values, timestamp: u64, bounds: &[f32]) -> Option<f64> {
+  let (slope, intercept) = linear_regression(values);
+  let current = intercept + slope * timestamp as f64;
+
+  bounds
+    .iter()
+    .map(|bound| (*bound as f64 - current) / slope)
+    .filter(|seconds| seconds.is_finite() && *seconds >= 0.0)
+    .reduce(f64::min)
+}
+
+pub fn compare(current: &Values, previous: Option<&Values>, method: &CompareMethod) -> f32 {
+  let (current, previous) = match previous {
+    Some(previous) => (avg(current), avg(previous)),
+    None => return f32::NAN,
+  };
+
+  match method {
+    CompareMethod::Absolute => current - previous,
+    CompareMethod::Relative => (current - previous) / previous.abs() * 100.0,
+  }
+}
+
+/// Percentage of samples matching condition
+pub fn duty_cycle(values: &Values, condition: &Condition, threshold: f32) -> f32 {
+  let matching = values
+    .iter()
+    .filter(|(_, v)| condition.matches(*v, threshold, false))
+    .count();
+
+  matching as f32 / values.len() as f32 * 100.0
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn get_test_values() -> Values {
+    vec![(0, 4.0), (10, 1.0), (20, 3.0), (30, 2.0)]
+  }
+
+  #[test]
+  fn simple_reducers() {
+    let values = get_test_values();
+
+    assert_eq!(2.5, avg(&values));
+    assert_eq!(1.0, min(&values));
+    assert_eq!(4.0, max(&values));
+    assert_eq!(2.0, last(&values));
+    assert_eq!(10.0, sum(&values));
+    assert_eq!(4.0, count(&values));
+  }
+
+  #[test]
+  fn empty_values() {
+    let values = vec![];
+
+    assert!(avg(&values).is_nan());
+    assert_eq!(0.0, last(&values));
+    assert_eq!(0.0, sum(&values));
+    assert_eq!(0.0, count(&values));
+  }
+
+  fn assert_close(expected: f32, actual: f32) {
+    assert!(
+      (expected - actual).abs() < 1e-5,
+      "expected {}, got {}",
+      expected,
+      actual
+    );
+  }
+
+  #[test]
+  fn percentile_interpolates_between_ranks() {
+    let values = get_test_values();
+
+    assert_eq!(1.0, percentile(&values, 0.0));
+    assert_eq!(2.5, percentile(&values, 0.5));
+    assert_close(3.85, percentile(&values, 0.95));
+    assert_eq!(4.0, percentile(&values, 1.0));
+    assert!(percentile(&vec![], 0.5).is_nan());
+  }
+
+  #[test]
+  fn change_methods() {
+    let values = get_test_values();
+
+    assert_eq!(
+      (-2.0, 0, 30),
+      change(&values, &ChangeMethod::Delta, &Condition::Greater)
+    );
+    assert_close(
+      -2.0 / 30.0,
+      change(&values, &ChangeMethod::Slope, &Condition::Greater).0,
+    );
+    assert_eq!(
+      (2.0, 10, 20),
+      change(&values, &ChangeMethod::MaxStep, &Condition::Greater)
+    );
+    assert_eq!((-3.0, 0, 10), change(&values, &ChangeMethod::MaxStep, &Condition::Less));
+  }
+
+  #[test]
+  fn change_without_enough_samples() {
+    assert!(change(&vec![], &ChangeMethod::Delta, &Condition::Greater).0.is_nan());
+    assert!(change(&vec![(10, 1.0)], &ChangeMethod::Slope, &Condition::Greater)
+      .0
+      .is_nan());
+  }
+
+  #[test]
+  fn mean_and_deviation() {
+    let values = vec![
+      (0, 2.0),
+      (10, 4.0),
+      (20, 4.0),
+      (30, 4.0),
+      (40, 5.0),
+      (50, 5.0),
+      (60, 7.0),
+      (70, 9.0),
+    ];
+
+    assert_eq!((5.0, 2.0), mean_std(&values));
+  }
+
+  #[test]
+  fn anomaly_against_baseline() {
+    let values = vec![(0, 2.0), (10, 4.0), (20, 2.0), (30, 4.0), (40, 9.0), (50, 7.0)];
+
+    assert_eq!((5.0, 3.0, 1.0), anomaly(&values, 40, 0.0));
+    assert!(anomaly(&values, 40, 1.0).0.is_nan());
+  }
+
+  #[test]
+  fn anomaly_of_flat_baseline() {
+    let values = vec![(0, 5.0), (10, 5.0), (20, 5.0), (30, 6.0)];
+    let (z_score, mean, std) = anomaly(&values, 30, 0.0);
+
+    assert!(z_score.is_nan());
+    assert_eq!(5.0, mean);
+    assert_eq!(0.0, std);
+  }
+
+  #[test]
+  fn forecast_of_linear_values() {
+    let values = vec![(0, 0.0), (10, 1.0), (20, 2.0)];
+
+    assert_close(5.0, forecast(&values, 50));
+
+    let seconds = time_to_cross(&values, 20, &[1.0, 5.0]).unwrap();
+    assert!((seconds - 30.0).abs() < 1e-6);
+    assert_eq!(None, time_to_cross(&values, 20, &[1.0]));
+    assert_eq!(None, time_to_cross(&vec![(0, 1.0), (10, 1.0)], 10, &[2.0]));
+  }
+
+  #[test]
+  fn compare_with_previous_period() {
+    let current = vec![(100, 3.0), (110, 5.0)];
+    let previous = vec![(0, 2.0), (10, 2.0)];
+
+    assert_eq!(2.0, compare(&current, Some(&previous), &CompareMethod::Absolute));
+    assert_eq!(100.0, compare(&current, Some(&previous), &CompareMethod::Relative));
+    assert!(compare(&current, None, &CompareMethod::Absolute).is_nan());
+  }
+
+  #[test]
+  fn duty_cycle_percentage() {
+    let values = get_test_values();
+
+    assert_eq!(50.0, duty_cycle(&values, &Condition::Greater, 2.0));
+    assert_eq!(25.0, duty_cycle(&values, &Condition::Less, 2.0));
+  }
+}
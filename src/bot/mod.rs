@@ -95,7 +95,20 @@ async fn status(cx: &Context) -> anyhow::Result<teloxide::prelude::Message> {
       }
 
       let duration = formatted_elapsed(state.status_last_changed(name.clone()));
-      let message = format!("\n{} {name}: for {}", status.emoji(), duration);
+      let message = match state.values.get(name) {
+        // Value was calculated before storage failed, error is more useful
+        _ if matches!(status, AlertStatus::DatasourceError(_)) => {
+          format!("\n{} {name} ({}): for {}", status.emoji(), status, duration)
+        }
+        Some(value) => format!(
+          "\n{} {name} ({} = {:.2}): for {}",
+          status.emoji(),
+          alert.condition.reducer_name(),
+          value,
+          duration
+        ),
+        None => format!("\n{} {name}: for {}", status.emoji(), duration),
+      };
 
       if response.len() + message.len() > 4096 {
         cx.answer(response.trim()).await?;
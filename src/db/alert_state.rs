@@ -18,6 +18,33 @@ pub struct AlertState {
   #[serde(default)]
   pub status_last_repeated: u64,
 
+  #[serde(default)]
+  pub critical_last_repeated: u64,
+
+  // When label started breaching the condition, used for `for_s` of alert
+  #[serde(default)]
+  pub pending_since: HashMap<String, i64>,
+
+  // When firing label stopped breaching the condition, used for `keep_firing_for_s` of alert
+  #[serde(default)]
+  pub resolving_since: HashMap<String, i64>,
+
+  // Number of resolves held back by `keep_firing_for_s`
+  #[serde(default)]
+  pub suppressed_resolves: HashMap<String, u32>,
+
+  // Last reduced value of each label, used for status overview
+  #[serde(default)]
+  pub values: HashMap<String, f32>,
+
+  // Additional information about last evaluation of each label, used for notifications
+  #[serde(default)]
+  pub details: HashMap<String, String>,
+
+  // Breaching labels of aggregate alert, drawn together on the chart
+  #[serde(default)]
+  pub aggregated_labels: Vec<String>,
+
   #[serde(default)]
   pub counter: u32,
 }
@@ -36,9 +63,24 @@ impl AlertState {
     chrono::DateTime::from_utc(naive, chrono::Utc)
   }
 
+  /// Forgets label with all its timers and values
+  pub fn remove_label(&mut self, label: &str) {
+    self.status.remove(label);
+    self.status_last_changed.remove(label);
+    self.pending_since.remove(label);
+    self.resolving_since.remove(label);
+    self.suppressed_resolves.remove(label);
+    self.values.remove(label);
+    self.details.remove(label);
+  }
+
   pub fn update_repeat(&mut self) {
     self.status_last_repeated = chrono::Utc::now().timestamp() as u64;
   }
+
+  pub fn update_critical_repeat(&mut self) {
+    self.critical_last_repeated = chrono::Utc::now().timestamp() as u64;
+  }
 }
 
 ////
@@ -4,8 +4,11 @@ type DateTime = chrono::DateTime<chrono::Utc>;
 
 pub fn formatted_elapsed(to: DateTime) -> String {
   let from = chrono::Utc::now().with_nanosecond(0).unwrap_or_else(chrono::Utc::now);
-  let mut duration = from - to;
 
+  formatted_duration(from - to)
+}
+
+pub fn formatted_duration(mut duration: chrono::Duration) -> String {
   let mut result = "".to_owned();
 
   result += &localize(duration.num_weeks(), "", "1 week ", "weeks ");
@@ -44,3 +47,90 @@ pub fn localize<'a>(num: i64, zero: &'a str, one: &'a str, many: &'a str) -> Str
     format!("{} {}", num, many)
   }
 }
+
+/// Parses duration in Prometheus format, like `30s`, `1m30s`, `500ms` or plain seconds `15` and `1.5`.
+/// Fractions of second are rounded up, zero duration is invalid
+pub fn parse_duration_s(duration: &str) -> Option<u64> {
+  if let Ok(seconds) = duration.parse::<f64>() {
+    if seconds.is_finite() && seconds > 0.0 {
+      return Some(seconds.ceil() as u64);
+    }
+
+    return None;
+  }
+
+  let mut result_ms = 0;
+  let mut rest = duration;
+  while !rest.is_empty() {
+    let length = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+    let number: u64 = rest[..length].parse().ok()?;
+    rest = &rest[length..];
+
+    let unit = rest.find(|c: char| c.is_ascii_digit()).unwrap_or(rest.len());
+    result_ms += number
+      * match &rest[..unit] {
+        "ms" => 1,
+        "s" => 1000,
+        "m" => 60 * 1000,
+        "h" => 60 * 60 * 1000,
+        "d" => 24 * 60 * 60 * 1000,
+        "w" => 7 * 24 * 60 * 60 * 1000,
+        _ => return None,
+      };
+    rest = &rest[unit..];
+  }
+
+  if result_ms == 0 {
+    None
+  } else {
+    Some(result_ms / 1000 + u64::from(result_ms % 1000 > 0))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse_plain_seconds() {
+    assert_eq!(Some(15), parse_duration_s("15"));
+    assert_eq!(Some(2), parse_duration_s("1.5"));
+  }
+
+  #[test]
+  fn parse_units() {
+    assert_eq!(Some(30), parse_duration_s("30s"));
+    assert_eq!(Some(5 * 60), parse_duration_s("5m"));
+    assert_eq!(Some(2 * 60 * 60), parse_duration_s("2h"));
+    assert_eq!(Some(24 * 60 * 60), parse_duration_s("1d"));
+    assert_eq!(Some(7 * 24 * 60 * 60), parse_duration_s("1w"));
+    assert_eq!(Some(1), parse_duration_s("500ms"));
+  }
+
+  #[test]
+  fn parse_compound() {
+    assert_eq!(Some(90), parse_duration_s("1m30s"));
+    assert_eq!(Some(26 * 60 * 60 + 5), parse_duration_s("1d2h5s"));
+    assert_eq!(Some(2), parse_duration_s("1s500ms"));
+  }
+
+  #[test]
+  fn reject_zero() {
+    assert_eq!(None, parse_duration_s("0"));
+    assert_eq!(None, parse_duration_s("0.0"));
+    assert_eq!(None, parse_duration_s("0s"));
+    assert_eq!(None, parse_duration_s("0m0s"));
+  }
+
+  #[test]
+  fn reject_invalid() {
+    assert_eq!(None, parse_duration_s(""));
+    assert_eq!(None, parse_duration_s("s"));
+    assert_eq!(None, parse_duration_s("1m30"));
+    assert_eq!(None, parse_duration_s("5y"));
+    assert_eq!(None, parse_duration_s("-5"));
+    assert_eq!(None, parse_duration_s("-5s"));
+    assert_eq!(None, parse_duration_s("1.5m"));
+    assert_eq!(None, parse_duration_s("inf"));
+  }
+}